@@ -0,0 +1,508 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::prelude::*;
+
+use super::export::Chunk;
+
+/// Loop, marker, tempo and description chunks of a WAV file.
+/// Positions are in frames at the sample rate of the file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct WavMetadata {
+    /// `smpl` chunk loops
+    pub loops: Vec<SampleLoop>,
+    /// `cue ` chunk markers, labels are stored in a `LIST`/`adtl` chunk
+    pub cues: Vec<CueMarker>,
+    /// `acid` chunk
+    pub acid: Option<AcidInfo>,
+    /// BWF `bext` chunk
+    pub bext: Option<BroadcastInfo>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum LoopKind {
+    #[default]
+    Forward,
+    PingPong,
+    Backward,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SampleLoop {
+    /// Frames looped over, the end is exclusive
+    pub range: Range<u32>,
+    #[serde(default)]
+    pub kind: LoopKind,
+    /// Number of repetitions, 0 loops forever
+    #[serde(default)]
+    pub play_count: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CueMarker {
+    pub id: u32,
+    pub position: u32,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AcidInfo {
+    /// Beats per minute
+    pub tempo: f32,
+    pub beats: u32,
+    /// (numerator, denominator)
+    pub meter: (u16, u16),
+    /// MIDI note of the root, `None` for non tonal material
+    pub root_note: Option<u16>,
+    /// One shots are not stretched to the project tempo
+    pub one_shot: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct BroadcastInfo {
+    /// Up to 256 ASCII characters, we store the prompt there
+    pub description: String,
+    /// Up to 32 ASCII characters
+    pub originator: String,
+    /// Up to 32 ASCII characters
+    pub originator_reference: String,
+    /// `yyyy-mm-dd`
+    pub origination_date: String,
+    /// `hh:mm:ss`
+    pub origination_time: String,
+    /// First sample count since midnight
+    pub time_reference: u64,
+}
+
+impl AcidInfo {
+    /// Loop of `frames` frames at `tempo`, the beat count is rounded to the closest integer
+    pub fn looped(tempo: f32, frames: usize, sample_rate: u32, meter: (u16, u16)) -> Self {
+        let beats = frames as f64 / sample_rate as f64 * tempo as f64 / 60.0;
+        Self {
+            tempo,
+            beats: beats.round() as u32,
+            meter,
+            root_note: None,
+            one_shot: false,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut flags = 0u32;
+        if self.one_shot {
+            flags |= 0x01;
+        }
+        if self.root_note.is_some() {
+            flags |= 0x02;
+        }
+        // stretch, when not a one shot
+        if !self.one_shot {
+            flags |= 0x04;
+        }
+        let mut data = flags.to_le_bytes().to_vec();
+        data.extend_from_slice(&self.root_note.unwrap_or(60).to_le_bytes());
+        data.extend_from_slice(&0x8000u16.to_le_bytes());
+        data.extend_from_slice(&0f32.to_le_bytes());
+        data.extend_from_slice(&self.beats.to_le_bytes());
+        data.extend_from_slice(&self.meter.1.to_le_bytes());
+        data.extend_from_slice(&self.meter.0.to_le_bytes());
+        data.extend_from_slice(&self.tempo.to_le_bytes());
+        data
+    }
+}
+
+impl BroadcastInfo {
+    /// Description dated now, `originator` names the generating software
+    pub fn describe(description: &str, originator: &str) -> Self {
+        let now = Local::now();
+        Self {
+            description: description.to_string(),
+            originator: originator.to_string(),
+            origination_date: now.format("%Y-%m-%d").to_string(),
+            origination_time: now.format("%H:%M:%S").to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Version 1 `bext` chunk without coding history
+    fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        push_ascii_field(&mut data, &self.description, 256);
+        push_ascii_field(&mut data, &self.originator, 32);
+        push_ascii_field(&mut data, &self.originator_reference, 32);
+        push_ascii_field(&mut data, &self.origination_date, 10);
+        push_ascii_field(&mut data, &self.origination_time, 8);
+        data.extend_from_slice(&self.time_reference.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        // UMID and reserved bytes
+        data.extend_from_slice(&[0; 64 + 190]);
+        data
+    }
+}
+
+impl WavMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.loops.is_empty() && self.cues.is_empty() && self.acid.is_none() && self.bext.is_none()
+    }
+
+    /// Positions moved to another sample rate
+    pub fn rescaled(&self, from: u32, to: u32) -> Self {
+        let scale = |position: u32| (position as f64 * to as f64 / from as f64).round() as u32;
+        let mut metadata = self.clone();
+        for sample_loop in metadata.loops.iter_mut() {
+            sample_loop.range = scale(sample_loop.range.start)..scale(sample_loop.range.end);
+        }
+        for cue in metadata.cues.iter_mut() {
+            cue.position = scale(cue.position);
+        }
+        metadata
+    }
+
+    /// Positions after keeping only `frames` of a clip at `sample_rate`.
+    /// Loops not entirely inside `frames` and cues outside of it are dropped, the `acid` beat count follows the new length.
+    pub fn sliced(&self, frames: Range<usize>, sample_rate: u32) -> Self {
+        let start = frames.start as u32;
+        let end = frames.end as u32;
+        let mut metadata = self.clone();
+        metadata.loops.retain(|sample_loop| sample_loop.range.start >= start && sample_loop.range.end <= end);
+        for sample_loop in metadata.loops.iter_mut() {
+            sample_loop.range = sample_loop.range.start - start..sample_loop.range.end - start;
+        }
+        metadata.cues.retain(|cue| (start..=end).contains(&cue.position));
+        for cue in metadata.cues.iter_mut() {
+            cue.position -= start;
+        }
+        if let Some(acid) = metadata.acid.as_mut().filter(|acid| !acid.one_shot) {
+            acid.beats = AcidInfo::looped(acid.tempo, frames.len(), sample_rate, acid.meter).beats;
+        }
+        metadata
+    }
+
+    /// Positions after reversing a clip of `frames` frames
+    pub fn reversed(&self, frames: usize) -> Self {
+        let frames = frames as u32;
+        let mut metadata = self.clone();
+        for sample_loop in metadata.loops.iter_mut() {
+            sample_loop.range = frames.saturating_sub(sample_loop.range.end)..frames.saturating_sub(sample_loop.range.start);
+        }
+        for cue in metadata.cues.iter_mut() {
+            cue.position = frames.saturating_sub(cue.position);
+        }
+        metadata
+    }
+
+    /// Chunks written before the `data` chunk
+    pub(crate) fn leading_chunks(&self) -> Vec<Chunk> {
+        self.bext.iter().map(|bext| Chunk::new(b"bext", bext.encode())).collect()
+    }
+
+    /// Chunks written after the `data` chunk
+    pub(crate) fn trailing_chunks(&self, sample_rate: u32) -> Vec<Chunk> {
+        let mut chunks = vec![];
+        if !self.loops.is_empty() {
+            chunks.push(Chunk::new(b"smpl", self.encode_smpl(sample_rate)));
+        }
+        if !self.cues.is_empty() {
+            chunks.push(Chunk::new(b"cue ", self.encode_cue()));
+            let mut adtl = b"adtl".to_vec();
+            for cue in self.cues.iter() {
+                if let Some(label) = cue.label.as_ref() {
+                    let mut labl = cue.id.to_le_bytes().to_vec();
+                    labl.extend_from_slice(label.as_bytes());
+                    labl.push(0);
+                    push_sub_chunk(&mut adtl, b"labl", &labl);
+                }
+            }
+            if adtl.len() > 4 {
+                chunks.push(Chunk::new(b"LIST", adtl));
+            }
+        }
+        if let Some(acid) = self.acid.as_ref() {
+            chunks.push(Chunk::new(b"acid", acid.encode()));
+        }
+        chunks
+    }
+
+    fn encode_smpl(&self, sample_rate: u32) -> Vec<u8> {
+        let mut data = vec![];
+        let period = match sample_rate {
+            0 => 0,
+            rate => 1_000_000_000 / rate,
+        };
+        // manufacturer, product, sample period, MIDI unity note (C4), pitch fraction, SMPTE format and offset, loop count, sampler data size
+        for value in [0, 0, period, 60, 0, 0, 0, self.loops.len() as u32, 0] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for (id, sample_loop) in self.loops.iter().enumerate() {
+            let kind: u32 = match sample_loop.kind {
+                LoopKind::Forward => 0,
+                LoopKind::PingPong => 1,
+                LoopKind::Backward => 2,
+            };
+            // the loop end is inclusive in the chunk
+            let end = std::cmp::max(sample_loop.range.end, sample_loop.range.start + 1) - 1;
+            for value in [id as u32, kind, sample_loop.range.start, end, 0, sample_loop.play_count] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    fn encode_cue(&self) -> Vec<u8> {
+        let mut data = (self.cues.len() as u32).to_le_bytes().to_vec();
+        for cue in self.cues.iter() {
+            data.extend_from_slice(&cue.id.to_le_bytes());
+            data.extend_from_slice(&cue.position.to_le_bytes());
+            data.extend_from_slice(b"data");
+            // chunk start, block start
+            data.extend_from_slice(&[0; 8]);
+            data.extend_from_slice(&cue.position.to_le_bytes());
+        }
+        data
+    }
+
+    fn decode_chunk(&mut self, id: &[u8; 4], data: &[u8]) {
+        match id {
+            b"smpl" => {
+                let count = le_u32(data, 28) as usize;
+                self.loops = (0..count)
+                    .map_while(|index| {
+                        let offset = 36 + index * 24;
+                        (offset + 24 <= data.len()).then(|| {
+                            let kind = match le_u32(data, offset + 4) {
+                                1 => LoopKind::PingPong,
+                                2 => LoopKind::Backward,
+                                _ => LoopKind::Forward,
+                            };
+                            let start = le_u32(data, offset + 8);
+                            let end = le_u32(data, offset + 12).saturating_add(1);
+                            SampleLoop {
+                                range: start..end,
+                                kind,
+                                play_count: le_u32(data, offset + 20),
+                            }
+                        })
+                    })
+                    .collect();
+            }
+            b"cue " => {
+                let count = le_u32(data, 0) as usize;
+                let labels: HashMap<u32, Option<String>> =
+                    self.cues.drain(..).map(|cue| (cue.id, cue.label)).collect();
+                self.cues = (0..count)
+                    .map_while(|index| {
+                        let offset = 4 + index * 24;
+                        (offset + 24 <= data.len()).then(|| {
+                            let id = le_u32(data, offset);
+                            CueMarker {
+                                id,
+                                position: le_u32(data, offset + 20),
+                                label: labels.get(&id).cloned().flatten(),
+                            }
+                        })
+                    })
+                    .collect();
+            }
+            b"LIST" if data.starts_with(b"adtl") => {
+                let mut offset = 4;
+                while offset + 8 <= data.len() {
+                    let size = le_u32(data, offset + 4) as usize;
+                    let body = &data[offset + 8..std::cmp::min(offset + 8 + size, data.len())];
+                    if &data[offset..offset + 4] == b"labl" && body.len() >= 4 {
+                        let id = le_u32(body, 0);
+                        let label = ascii_field(&body[4..]);
+                        match self.cues.iter_mut().find(|cue| cue.id == id) {
+                            Some(cue) => cue.label = Some(label),
+                            // labels may come before the cue chunk
+                            None => self.cues.push(CueMarker { id, position: 0, label: Some(label) }),
+                        }
+                    }
+                    offset += 8 + size + size % 2;
+                }
+            }
+            b"acid" if data.len() >= 24 => {
+                let flags = le_u32(data, 0);
+                let root_note = u16::from_le_bytes([data[4], data[5]]);
+                self.acid = Some(AcidInfo {
+                    tempo: f32::from_le_bytes([data[20], data[21], data[22], data[23]]),
+                    beats: le_u32(data, 12),
+                    meter: (
+                        u16::from_le_bytes([data[18], data[19]]),
+                        u16::from_le_bytes([data[16], data[17]]),
+                    ),
+                    root_note: (flags & 0x02 != 0).then_some(root_note),
+                    one_shot: flags & 0x01 != 0,
+                });
+            }
+            b"bext" if data.len() >= 346 => {
+                self.bext = Some(BroadcastInfo {
+                    description: ascii_field(&data[0..256]),
+                    originator: ascii_field(&data[256..288]),
+                    originator_reference: ascii_field(&data[288..320]),
+                    origination_date: ascii_field(&data[320..330]),
+                    origination_time: ascii_field(&data[330..338]),
+                    time_reference: u64::from_le_bytes(data[338..346].try_into().unwrap_or_default()),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Read the loop, marker, tempo and description chunks of a WAV file, other chunks are skipped
+/* IMPORTANT: this is not using async I/O */
+pub fn read_wav_metadata(path: &Path) -> CaResult<WavMetadata> {
+    let file = std::fs::File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut file = std::io::BufReader::new(file);
+    let mut header = [0; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(CaError::UnsupportedFormat("not a RIFF/WAVE file".to_string()));
+    }
+    let mut metadata = WavMetadata::default();
+    let mut chunk_header = [0; 8];
+    loop {
+        match file.read_exact(&mut chunk_header) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        let id: [u8; 4] = [chunk_header[0], chunk_header[1], chunk_header[2], chunk_header[3]];
+        let size = le_u32(&chunk_header, 4) as u64;
+        // the size is read from the file, a chunk claiming more than what is left is truncated or corrupted
+        if size > length.saturating_sub(file.stream_position()?) {
+            break;
+        }
+        match &id {
+            b"smpl" | b"cue " | b"LIST" | b"acid" | b"bext" => {
+                let mut data = vec![0; size as usize];
+                file.read_exact(&mut data)?;
+                if size % 2 == 1 {
+                    file.seek(SeekFrom::Current(1))?;
+                }
+                metadata.decode_chunk(&id, &data);
+            }
+            _ => {
+                file.seek(SeekFrom::Current((size + size % 2) as i64))?;
+            }
+        }
+    }
+    Ok(metadata)
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .unwrap_or_default()
+}
+
+fn push_sub_chunk(buffer: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    buffer.extend_from_slice(id);
+    buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        buffer.push(0);
+    }
+}
+
+/// Fixed size field, non ASCII characters are replaced by `?` and the text is zero padded
+fn push_ascii_field(buffer: &mut Vec<u8>, text: &str, size: usize) {
+    let mut field: Vec<u8> = text
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+        .take(size)
+        .collect();
+    field.resize(size, 0);
+    buffer.extend_from_slice(&field);
+}
+
+fn ascii_field(data: &[u8]) -> String {
+    let end = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::export::{encode, ExportOptions, SampleEncoding};
+    use crate::audio::RawAudioSample;
+
+    fn metadata() -> WavMetadata {
+        WavMetadata {
+            loops: vec![SampleLoop {
+                range: 100..900,
+                kind: LoopKind::PingPong,
+                play_count: 2,
+            }],
+            cues: vec![
+                CueMarker {
+                    id: 1,
+                    position: 0,
+                    label: Some("intro".to_string()),
+                },
+                CueMarker {
+                    id: 2,
+                    position: 500,
+                    label: None,
+                },
+            ],
+            acid: Some(AcidInfo {
+                root_note: Some(57),
+                ..AcidInfo::looped(120.0, 1000, 1000, (4, 4))
+            }),
+            bext: Some(BroadcastInfo {
+                description: "dark techno loop".to_string(),
+                originator: "crovai".to_string(),
+                origination_date: "2024-01-31".to_string(),
+                origination_time: "12:34:56".to_string(),
+                time_reference: 42,
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn chunks_roundtrip_through_a_wav_file() {
+        let directory = TempDir::new("crovai_chunks").unwrap();
+        for encoding in [SampleEncoding::Int16, SampleEncoding::Float32] {
+            let path = directory.path().join("clip.wav");
+            let options = ExportOptions::wav(encoding).with_wav_metadata(metadata());
+            let sample = RawAudioSample::Mono(vec![0.25; 1001]);
+            std::fs::write(&path, encode(sample, 1000, &options).unwrap()).unwrap();
+            assert_eq!(read_wav_metadata(&path).unwrap(), metadata());
+            // the audio is still readable by a regular decoder
+            assert_eq!(hound::WavReader::open(&path).unwrap().duration(), 1001);
+        }
+    }
+
+    #[test]
+    fn oversized_chunks_are_not_read() {
+        let directory = TempDir::new("crovai_chunks").unwrap();
+        let path = directory.path().join("crafted.wav");
+        let mut data = b"RIFF\0\0\0\0WAVE".to_vec();
+        data.extend_from_slice(b"smpl");
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&[0; 64]);
+        std::fs::write(&path, data).unwrap();
+        assert_eq!(read_wav_metadata(&path).unwrap(), WavMetadata::default());
+    }
+
+    #[test]
+    fn non_ascii_text_is_replaced() {
+        let mut metadata = WavMetadata::default();
+        let bext = BroadcastInfo {
+            description: "kick é".to_string(),
+            ..Default::default()
+        };
+        metadata.decode_chunk(b"bext", &bext.encode());
+        assert_eq!(metadata.bext.unwrap().description, "kick ?");
+    }
+
+    #[test]
+    fn rescaled_positions_follow_the_sample_rate() {
+        let rescaled = metadata().rescaled(1000, 2000);
+        assert_eq!(rescaled.loops[0].range, 200..1800);
+        assert_eq!(rescaled.cues[1].position, 1000);
+    }
+}
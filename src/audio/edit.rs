@@ -0,0 +1,278 @@
+use crate::prelude::*;
+
+use super::chunks::WavMetadata;
+use super::RawAudioSample;
+
+/// Non destructive edit, recorded in the metadata of the asset it produced.
+/// Times are in seconds, levels in dBFS.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "op")]
+pub enum EditOperation {
+    /// Keep `start..end`, `end` is clamped to the length of the clip
+    Slice { start: f64, end: f64 },
+    /// Drop the leading and trailing frames quieter than `threshold_db` on every channel
+    TrimSilence { threshold_db: f32 },
+    Normalize { mode: NormalizeMode, target_db: f32 },
+    FadeIn { seconds: f64 },
+    FadeOut { seconds: f64 },
+    Reverse,
+    Gain { db: f32 },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum NormalizeMode {
+    /// Highest absolute sample of every channel
+    Peak,
+    /// Root mean square over every channel
+    Rms,
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+impl EditOperation {
+    pub fn apply(&self, sample: &RawAudioSample, sample_rate: u32) -> CaResult<RawAudioSample> {
+        let frames = |seconds: f64| (seconds.max(0.0) * sample_rate as f64).round() as usize;
+        match self {
+            EditOperation::Slice { .. } | EditOperation::TrimSilence { .. } => {
+                Ok(sample.slice(self.kept_frames(sample, sample_rate)?))
+            }
+            EditOperation::Normalize { mode, target_db } => sample.normalize(*mode, db_to_gain(*target_db)),
+            EditOperation::FadeIn { seconds } => Ok(sample.fade_in(frames(*seconds))),
+            EditOperation::FadeOut { seconds } => Ok(sample.fade_out(frames(*seconds))),
+            EditOperation::Reverse => Ok(sample.reverse()),
+            EditOperation::Gain { db } => Ok(sample.gain(db_to_gain(*db))),
+        }
+    }
+
+    /// Loop and marker positions of `metadata` once the operation is applied to `sample`
+    pub fn remap(&self, metadata: &WavMetadata, sample: &RawAudioSample, sample_rate: u32) -> CaResult<WavMetadata> {
+        match self {
+            EditOperation::Slice { .. } | EditOperation::TrimSilence { .. } => {
+                Ok(metadata.sliced(self.kept_frames(sample, sample_rate)?, sample_rate))
+            }
+            EditOperation::Reverse => Ok(metadata.reversed(sample.frames())),
+            _ => Ok(metadata.clone()),
+        }
+    }
+
+    /// Frames of `sample` kept by a slice or a silence trim, every frame for the other operations
+    fn kept_frames(&self, sample: &RawAudioSample, sample_rate: u32) -> CaResult<Range<usize>> {
+        let frames = |seconds: f64| (seconds.max(0.0) * sample_rate as f64).round() as usize;
+        match self {
+            EditOperation::Slice { start, end } => {
+                let valid = *start >= 0.0 && start < end;
+                if !valid {
+                    return Err(CaError::InvalidEdit(format!("empty or negative slice {}..{}", start, end)));
+                }
+                let range = frames(*start)..std::cmp::min(frames(*end), sample.frames());
+                if range.is_empty() {
+                    return Err(CaError::InvalidEdit(format!("slice {}..{} is past the end of the clip", start, end)));
+                }
+                Ok(range)
+            }
+            EditOperation::TrimSilence { threshold_db } => sample.loud_frames(db_to_gain(*threshold_db)),
+            _ => Ok(0..sample.frames()),
+        }
+    }
+}
+
+impl RawAudioSample {
+    fn map_channels<F: Fn(&[f32]) -> Vec<f32>>(&self, map: F) -> RawAudioSample {
+        match self {
+            RawAudioSample::Mono(signal) => RawAudioSample::Mono(map(signal)),
+            RawAudioSample::Stereo(left, right) => RawAudioSample::Stereo(map(left), map(right)),
+        }
+    }
+
+    fn channel_slices(&self) -> Vec<&[f32]> {
+        match self {
+            RawAudioSample::Mono(signal) => vec![signal],
+            RawAudioSample::Stereo(left, right) => vec![left, right],
+        }
+    }
+
+    /// Frames in `range`, clamped to the length of the clip
+    pub fn slice(&self, range: Range<usize>) -> RawAudioSample {
+        let end = std::cmp::min(range.end, self.frames());
+        let start = std::cmp::min(range.start, end);
+        self.map_channels(|signal| signal[start..end].to_vec())
+    }
+
+    /// Drop the leading and trailing frames where every channel is below `threshold` (linear)
+    pub fn trim_silence(&self, threshold: f32) -> CaResult<RawAudioSample> {
+        Ok(self.slice(self.loud_frames(threshold)?))
+    }
+
+    /// Frames from the first to the last one where a channel is above `threshold` (linear)
+    fn loud_frames(&self, threshold: f32) -> CaResult<Range<usize>> {
+        let channels = self.channel_slices();
+        let loud = |frame: usize| channels.iter().any(|signal| signal[frame].abs() > threshold);
+        let frames = self.frames();
+        let start = (0..frames).find(|frame| loud(*frame)).ok_or(CaError::Empty)?;
+        let end = (0..frames).rev().find(|frame| loud(*frame)).unwrap_or(start) + 1;
+        Ok(start..end)
+    }
+
+    /// Scale the clip so its peak or RMS level reaches `target` (linear), silent clips are left untouched
+    pub fn normalize(&self, mode: NormalizeMode, target: f32) -> CaResult<RawAudioSample> {
+        let channels = self.channel_slices();
+        let level = match mode {
+            NormalizeMode::Peak => channels
+                .iter()
+                .flat_map(|signal| signal.iter())
+                .fold(0f32, |peak, sample| f32::max(peak, sample.abs())),
+            NormalizeMode::Rms => {
+                let count: usize = channels.iter().map(|signal| signal.len()).sum();
+                if count == 0 {
+                    return Err(CaError::Empty);
+                }
+                let power: f64 = channels
+                    .iter()
+                    .flat_map(|signal| signal.iter())
+                    .map(|sample| (*sample as f64) * (*sample as f64))
+                    .sum();
+                (power / count as f64).sqrt() as f32
+            }
+        };
+        if level == 0.0 {
+            return Ok(self.clone());
+        }
+        Ok(self.gain(target / level))
+    }
+
+    /// Linear fade over the first `frames` frames
+    pub fn fade_in(&self, frames: usize) -> RawAudioSample {
+        self.map_channels(|signal| {
+            let length = std::cmp::min(frames, signal.len());
+            signal
+                .iter()
+                .enumerate()
+                .map(|(index, sample)| match index < length {
+                    true => sample * index as f32 / length as f32,
+                    false => *sample,
+                })
+                .collect()
+        })
+    }
+
+    /// Linear fade over the last `frames` frames
+    pub fn fade_out(&self, frames: usize) -> RawAudioSample {
+        self.map_channels(|signal| {
+            let length = std::cmp::min(frames, signal.len());
+            let start = signal.len() - length;
+            signal
+                .iter()
+                .enumerate()
+                .map(|(index, sample)| match index >= start {
+                    true => sample * (signal.len() - 1 - index) as f32 / length as f32,
+                    false => *sample,
+                })
+                .collect()
+        })
+    }
+
+    pub fn reverse(&self) -> RawAudioSample {
+        self.map_channels(|signal| signal.iter().rev().copied().collect())
+    }
+
+    /// Multiply every sample by `gain` (linear), the result is not clipped
+    pub fn gain(&self, gain: f32) -> RawAudioSample {
+        self.map_channels(|signal| signal.iter().map(|sample| sample * gain).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::chunks::{CueMarker, SampleLoop};
+
+    fn ramp(frames: usize) -> RawAudioSample {
+        RawAudioSample::Mono((0..frames).map(|frame| frame as f32 / frames as f32).collect())
+    }
+
+    fn markers() -> WavMetadata {
+        WavMetadata {
+            loops: vec![
+                SampleLoop {
+                    range: 200..400,
+                    kind: Default::default(),
+                    play_count: 0,
+                },
+                SampleLoop {
+                    range: 50..150,
+                    kind: Default::default(),
+                    play_count: 0,
+                },
+            ],
+            cues: vec![
+                CueMarker {
+                    id: 1,
+                    position: 300,
+                    label: None,
+                },
+                CueMarker {
+                    id: 2,
+                    position: 900,
+                    label: None,
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn slice_keeps_the_requested_frames() {
+        let slice = EditOperation::Slice { start: 0.1, end: 0.5 };
+        let sliced = slice.apply(&ramp(1000), 1000).unwrap();
+        assert_eq!(sliced.frames(), 400);
+        assert!(EditOperation::Slice { start: 2.0, end: 3.0 }.apply(&ramp(1000), 1000).is_err());
+        assert!(EditOperation::Slice { start: 0.5, end: 0.5 }.apply(&ramp(1000), 1000).is_err());
+    }
+
+    #[test]
+    fn slice_moves_markers_and_drops_the_ones_outside() {
+        let slice = EditOperation::Slice { start: 0.1, end: 0.5 };
+        let remapped = slice.remap(&markers(), &ramp(1000), 1000).unwrap();
+        assert_eq!(remapped.loops.len(), 1);
+        assert_eq!(remapped.loops[0].range, 100..300);
+        assert_eq!(remapped.cues.len(), 1);
+        assert_eq!(remapped.cues[0].position, 200);
+    }
+
+    #[test]
+    fn trim_silence_moves_markers_like_a_slice() {
+        let mut signal = vec![0.0; 1000];
+        signal[100..800].fill(0.5);
+        let sample = RawAudioSample::Mono(signal);
+        let trim = EditOperation::TrimSilence { threshold_db: -60.0 };
+        assert_eq!(trim.apply(&sample, 1000).unwrap().frames(), 700);
+        let remapped = trim.remap(&markers(), &sample, 1000).unwrap();
+        assert_eq!(remapped.loops[0].range, 100..300);
+        assert_eq!(remapped.cues[0].position, 200);
+    }
+
+    #[test]
+    fn reverse_mirrors_markers() {
+        let reversed = EditOperation::Reverse.remap(&markers(), &ramp(1000), 1000).unwrap();
+        assert_eq!(reversed.loops[0].range, 600..800);
+        assert_eq!(reversed.cues[1].position, 100);
+        let sample = EditOperation::Reverse.apply(&ramp(4), 4).unwrap();
+        assert_eq!(sample, RawAudioSample::Mono(vec![0.75, 0.5, 0.25, 0.0]));
+    }
+
+    #[test]
+    fn normalize_and_gain_scale_levels() {
+        let sample = RawAudioSample::Stereo(vec![0.25, -0.5], vec![0.1, 0.0]);
+        let normalized = EditOperation::Normalize {
+            mode: NormalizeMode::Peak,
+            target_db: 0.0,
+        }
+        .apply(&sample, 1)
+        .unwrap();
+        assert_eq!(normalized, RawAudioSample::Stereo(vec![0.5, -1.0], vec![0.2, 0.0]));
+        let attenuated = EditOperation::Gain { db: -6.0206 }.apply(&normalized, 1).unwrap();
+        assert!(attenuated.interleave().iter().zip(sample.interleave()).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+}
@@ -0,0 +1,424 @@
+use crate::prelude::*;
+use crate::engine::asset::Asset;
+
+use super::chunks::WavMetadata;
+use super::wav::{AudioFormat, SampleFormat};
+use super::RawAudioSample;
+
+/// Container written by `export`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Wav,
+    /// AIFF for integer encodings, AIFF-C `fl32` for 32 bits float
+    Aiff,
+    /// Headerless interleaved PCM
+    Raw(Endianness),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum SampleEncoding {
+    #[default]
+    Int16,
+    Int24,
+    Float32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum ChannelLayout {
+    /// Keep the channels of the source
+    #[default]
+    Keep,
+    /// Average of the channels
+    Mono,
+    /// Mono sources are duplicated on both channels
+    Stereo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub encoding: SampleEncoding,
+    /// Resample to this rate, `None` keeps the source rate
+    pub sample_rate: Option<u32>,
+    pub channels: ChannelLayout,
+    /// Loops, markers, tempo and description embedded in WAV exports, ignored by the other formats.
+    /// Positions are relative to the source sample rate.
+    pub wav_metadata: Option<WavMetadata>,
+}
+
+impl SampleEncoding {
+    /// Closest encoding to a decoded format, 8 bits are widened and 32 bits integers stored as float
+    pub fn from_format(format: AudioFormat) -> Self {
+        match (format.sample_format, format.bits_per_sample) {
+            (SampleFormat::Int, 0..=16) => SampleEncoding::Int16,
+            (SampleFormat::Int, 17..=24) => SampleEncoding::Int24,
+            _ => SampleEncoding::Float32,
+        }
+    }
+
+    pub fn bits_per_sample(&self) -> u16 {
+        match self {
+            SampleEncoding::Int16 => 16,
+            SampleEncoding::Int24 => 24,
+            SampleEncoding::Float32 => 32,
+        }
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        self.bits_per_sample() as usize / 8
+    }
+
+    /// Append a sample clamped to [-1, 1]. Integers are scaled by 2^(bits - 1) like the decoder does,
+    /// -1.0 maps to the lowest integer and positive values saturate one step below 1.0.
+    fn push(&self, buffer: &mut Vec<u8>, sample: f32, endianness: Endianness) {
+        let sample = sample.clamp(-1.0, 1.0);
+        let integer = |bits: u16| {
+            let scale = (1i64 << (bits - 1)) as f64;
+            (sample as f64 * scale).round().clamp(-scale, scale - 1.0) as i32
+        };
+        match (self, endianness) {
+            (SampleEncoding::Int16, Endianness::Little) => buffer.extend_from_slice(&(integer(16) as i16).to_le_bytes()),
+            (SampleEncoding::Int16, Endianness::Big) => buffer.extend_from_slice(&(integer(16) as i16).to_be_bytes()),
+            (SampleEncoding::Int24, Endianness::Little) => buffer.extend_from_slice(&integer(24).to_le_bytes()[..3]),
+            (SampleEncoding::Int24, Endianness::Big) => buffer.extend_from_slice(&integer(24).to_be_bytes()[1..]),
+            (SampleEncoding::Float32, Endianness::Little) => buffer.extend_from_slice(&sample.to_le_bytes()),
+            (SampleEncoding::Float32, Endianness::Big) => buffer.extend_from_slice(&sample.to_be_bytes()),
+        }
+    }
+}
+
+impl ExportOptions {
+    pub fn wav(encoding: SampleEncoding) -> Self {
+        Self {
+            format: ExportFormat::Wav,
+            encoding,
+            ..Default::default()
+        }
+    }
+
+    pub fn aiff(encoding: SampleEncoding) -> Self {
+        Self {
+            format: ExportFormat::Aiff,
+            encoding,
+            ..Default::default()
+        }
+    }
+
+    pub fn raw(encoding: SampleEncoding, endianness: Endianness) -> Self {
+        Self {
+            format: ExportFormat::Raw(endianness),
+            encoding,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    pub fn with_channels(mut self, channels: ChannelLayout) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    pub fn with_wav_metadata(mut self, metadata: WavMetadata) -> Self {
+        self.wav_metadata = Some(metadata);
+        self
+    }
+}
+
+impl RawAudioSample {
+    pub fn with_layout(self, layout: ChannelLayout) -> RawAudioSample {
+        match (layout, self) {
+            (ChannelLayout::Mono, RawAudioSample::Stereo(left, right)) => {
+                RawAudioSample::Mono(left.iter().zip(right.iter()).map(|(left, right)| (left + right) * 0.5).collect())
+            }
+            (ChannelLayout::Stereo, RawAudioSample::Mono(signal)) => RawAudioSample::Stereo(signal.clone(), signal),
+            (_, sample) => sample,
+        }
+    }
+
+    /// Band limited resampling (Hann windowed sinc)
+    pub fn resample(&self, from: u32, to: u32) -> RawAudioSample {
+        if from == to || from == 0 || to == 0 {
+            return self.clone();
+        }
+        match self {
+            RawAudioSample::Mono(signal) => RawAudioSample::Mono(resample_channel(signal, from, to)),
+            RawAudioSample::Stereo(left, right) => {
+                RawAudioSample::Stereo(resample_channel(left, from, to), resample_channel(right, from, to))
+            }
+        }
+    }
+}
+
+/// Zero crossings of the sinc kept on each side of the interpolated sample
+const RESAMPLE_ZERO_CROSSINGS: f64 = 16.0;
+
+fn resample_channel(signal: &[f32], from: u32, to: u32) -> Vec<f32> {
+    let ratio = to as f64 / from as f64;
+    // lowpass at the lowest nyquist frequency to avoid aliasing when downsampling
+    let cutoff = ratio.min(1.0);
+    let radius = RESAMPLE_ZERO_CROSSINGS / cutoff;
+    let length = (signal.len() as f64 * ratio).ceil() as usize;
+    (0..length)
+        .map(|index| {
+            let position = index as f64 / ratio;
+            let first = (position - radius).ceil().max(0.0) as usize;
+            let last = std::cmp::min((position + radius).floor() as usize, signal.len().saturating_sub(1));
+            (first..=last)
+                .map(|input| {
+                    let distance = position - input as f64;
+                    let x = std::f64::consts::PI * cutoff * distance;
+                    let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+                    let window = 0.5 + 0.5 * (std::f64::consts::PI * distance / radius).cos();
+                    signal[input] as f64 * cutoff * sinc * window
+                })
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
+
+/// Encode `sample` in memory, see `export`
+pub fn encode(sample: RawAudioSample, sample_rate: u32, options: &ExportOptions) -> CaResult<Vec<u8>> {
+    let sample = sample.with_layout(options.channels);
+    let source_rate = sample_rate;
+    let (sample, sample_rate) = match options.sample_rate {
+        Some(0) => return Err(CaError::UnsupportedFormat("0 Hz sample rate".to_string())),
+        Some(rate) => (sample.resample(sample_rate, rate), rate),
+        None => (sample, sample_rate),
+    };
+    match options.format {
+        ExportFormat::Wav => {
+            let metadata = options
+                .wav_metadata
+                .as_ref()
+                .map(|metadata| metadata.rescaled(source_rate, sample_rate))
+                .unwrap_or_default();
+            Ok(encode_wav(&sample, sample_rate, options.encoding, &metadata))
+        }
+        ExportFormat::Aiff => Ok(encode_aiff(&sample, sample_rate, options.encoding)),
+        ExportFormat::Raw(endianness) => Ok(encode_pcm(&sample, options.encoding, endianness)),
+    }
+}
+
+/// Write `sample` to `path`, encoding runs on the blocking thread pool
+pub async fn export(sample: RawAudioSample, sample_rate: u32, path: &Path, options: &ExportOptions) -> CaResult<Asset> {
+    let options = options.clone();
+    let encoded = tokio::task::spawn_blocking(move || encode(sample, sample_rate, &options))
+        .await??;
+    tokio::fs::write(path, encoded).await?;
+    Ok(Asset::Stored { path: path.to_owned() })
+}
+
+fn encode_pcm(sample: &RawAudioSample, encoding: SampleEncoding, endianness: Endianness) -> Vec<u8> {
+    let interleaved = sample.interleave();
+    let mut buffer = Vec::with_capacity(interleaved.len() * encoding.bytes_per_sample());
+    for value in interleaved {
+        encoding.push(&mut buffer, value, endianness);
+    }
+    buffer
+}
+
+/// RIFF/AIFF chunk, the pad byte of odd sized chunks is added by `write_chunks`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn new(id: &[u8; 4], data: Vec<u8>) -> Self {
+        Self { id: *id, data }
+    }
+}
+
+/// `RIFF`/`FORM` container holding `chunks`
+fn write_chunks(container: &[u8; 4], form_type: &[u8; 4], chunks: &[Chunk], endianness: Endianness) -> Vec<u8> {
+    let size = |size: usize| match endianness {
+        Endianness::Little => (size as u32).to_le_bytes(),
+        Endianness::Big => (size as u32).to_be_bytes(),
+    };
+    let body: usize = chunks.iter().map(|chunk| 8 + chunk.data.len() + chunk.data.len() % 2).sum();
+    let mut buffer = Vec::with_capacity(12 + body);
+    buffer.extend_from_slice(container);
+    buffer.extend_from_slice(&size(4 + body));
+    buffer.extend_from_slice(form_type);
+    for chunk in chunks {
+        buffer.extend_from_slice(&chunk.id);
+        buffer.extend_from_slice(&size(chunk.data.len()));
+        buffer.extend_from_slice(&chunk.data);
+        if chunk.data.len() % 2 == 1 {
+            buffer.push(0);
+        }
+    }
+    buffer
+}
+
+fn encode_wav(sample: &RawAudioSample, sample_rate: u32, encoding: SampleEncoding, metadata: &WavMetadata) -> Vec<u8> {
+    let channels = sample.channels();
+    let block_align = channels * encoding.bytes_per_sample() as u16;
+    let format_tag: u16 = match encoding {
+        SampleEncoding::Float32 => 3,
+        _ => 1,
+    };
+    let mut fmt = vec![];
+    fmt.extend_from_slice(&format_tag.to_le_bytes());
+    fmt.extend_from_slice(&channels.to_le_bytes());
+    fmt.extend_from_slice(&sample_rate.to_le_bytes());
+    fmt.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    fmt.extend_from_slice(&block_align.to_le_bytes());
+    fmt.extend_from_slice(&encoding.bits_per_sample().to_le_bytes());
+
+    let mut chunks = vec![];
+    if encoding == SampleEncoding::Float32 {
+        // non PCM formats carry an extension size and a fact chunk
+        fmt.extend_from_slice(&0u16.to_le_bytes());
+        chunks.push(Chunk::new(b"fmt ", fmt));
+        chunks.push(Chunk::new(b"fact", (sample.frames() as u32).to_le_bytes().to_vec()));
+    } else {
+        chunks.push(Chunk::new(b"fmt ", fmt));
+    }
+    chunks.extend(metadata.leading_chunks());
+    chunks.push(Chunk::new(b"data", encode_pcm(sample, encoding, Endianness::Little)));
+    chunks.extend(metadata.trailing_chunks(sample_rate));
+    write_chunks(b"RIFF", b"WAVE", &chunks, Endianness::Little)
+}
+
+fn encode_aiff(sample: &RawAudioSample, sample_rate: u32, encoding: SampleEncoding) -> Vec<u8> {
+    let mut comm = vec![];
+    comm.extend_from_slice(&(sample.channels() as i16).to_be_bytes());
+    comm.extend_from_slice(&(sample.frames() as u32).to_be_bytes());
+    comm.extend_from_slice(&(encoding.bits_per_sample() as i16).to_be_bytes());
+    comm.extend_from_slice(&extended_sample_rate(sample_rate));
+
+    let mut ssnd = vec![0; 8];
+    ssnd.extend(encode_pcm(sample, encoding, Endianness::Big));
+
+    match encoding {
+        SampleEncoding::Float32 => {
+            comm.extend_from_slice(b"fl32");
+            // pascal string padded to an even length
+            let name = b"32-bit floating point";
+            comm.push(name.len() as u8);
+            comm.extend_from_slice(name);
+            if (name.len() + 1) % 2 == 1 {
+                comm.push(0);
+            }
+            let chunks = [
+                Chunk::new(b"FVER", 0xA280_5140u32.to_be_bytes().to_vec()),
+                Chunk::new(b"COMM", comm),
+                Chunk::new(b"SSND", ssnd),
+            ];
+            write_chunks(b"FORM", b"AIFC", &chunks, Endianness::Big)
+        }
+        _ => {
+            let chunks = [Chunk::new(b"COMM", comm), Chunk::new(b"SSND", ssnd)];
+            write_chunks(b"FORM", b"AIFF", &chunks, Endianness::Big)
+        }
+    }
+}
+
+/// 80 bits IEEE 754 extended precision, as stored in the AIFF `COMM` chunk
+fn extended_sample_rate(sample_rate: u32) -> [u8; 10] {
+    let mut bytes = [0; 10];
+    if sample_rate == 0 {
+        return bytes;
+    }
+    let exponent = 31 - sample_rate.leading_zeros();
+    let mantissa = (sample_rate as u64) << (63 - exponent);
+    bytes[..2].copy_from_slice(&((16383 + exponent) as u16).to_be_bytes());
+    bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::wav::decode_wav;
+
+    fn sample() -> RawAudioSample {
+        RawAudioSample::Stereo(vec![0.0, 0.5, -0.5, 1.0], vec![0.25, -0.25, -1.0, 0.125])
+    }
+
+    #[test]
+    fn wav_exports_decode_to_the_same_samples() {
+        for (encoding, tolerance) in [
+            (SampleEncoding::Int16, 1e-4),
+            (SampleEncoding::Int24, 1e-6),
+            (SampleEncoding::Float32, 0.0),
+        ] {
+            let encoded = encode(sample(), 8000, &ExportOptions::wav(encoding)).unwrap();
+            let decoded = decode_wav(hound::WavReader::new(std::io::Cursor::new(encoded)).unwrap()).unwrap();
+            assert_eq!(decoded.sample_rate, 8000);
+            assert_eq!(SampleEncoding::from_format(decoded.format), encoding);
+            let expected = sample().interleave();
+            let found = decoded.sample.interleave();
+            assert_eq!(found.len(), expected.len());
+            assert!(found.iter().zip(expected).all(|(found, expected)| (found - expected).abs() <= tolerance));
+        }
+    }
+
+    #[test]
+    fn raw_exports_follow_the_endianness() {
+        let sample = RawAudioSample::Mono(vec![1.0, -1.0]);
+        let little = encode(sample.clone(), 8000, &ExportOptions::raw(SampleEncoding::Int16, Endianness::Little));
+        assert_eq!(little.unwrap(), [0xff, 0x7f, 0x00, 0x80]);
+        let big = encode(sample, 8000, &ExportOptions::raw(SampleEncoding::Int24, Endianness::Big));
+        assert_eq!(big.unwrap(), [0x7f, 0xff, 0xff, 0x80, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn decoded_integers_are_encoded_back_unchanged() {
+        for encoding in [SampleEncoding::Int16, SampleEncoding::Int24] {
+            let options = ExportOptions::wav(encoding);
+            let full_scale = RawAudioSample::Mono(vec![-1.0, -0.5, 0.0, 0.5, 1.0]);
+            let mut encoded = encode(full_scale, 8000, &options).unwrap();
+            // every edit decodes then encodes again, it must not lose gain
+            for _ in 0..3 {
+                let decoded = decode_wav(hound::WavReader::new(std::io::Cursor::new(encoded.clone())).unwrap()).unwrap();
+                assert_eq!(decoded.sample.interleave()[..4], [-1.0, -0.5, 0.0, 0.5]);
+                let reencoded = encode(decoded.sample, 8000, &options).unwrap();
+                assert_eq!(reencoded, encoded);
+                encoded = reencoded;
+            }
+        }
+    }
+
+    #[test]
+    fn aiff_headers_describe_the_audio() {
+        let encoded = encode(sample(), 44100, &ExportOptions::aiff(SampleEncoding::Int16)).unwrap();
+        assert_eq!(&encoded[0..4], b"FORM");
+        assert_eq!(u32::from_be_bytes(encoded[4..8].try_into().unwrap()) as usize, encoded.len() - 8);
+        assert_eq!(&encoded[8..12], b"AIFF");
+        assert_eq!(&encoded[12..16], b"COMM");
+        assert_eq!(extended_sample_rate(44100), [0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        let float = encode(sample(), 44100, &ExportOptions::aiff(SampleEncoding::Float32)).unwrap();
+        assert_eq!(&float[8..12], b"AIFC");
+    }
+
+    #[test]
+    fn layout_and_rate_are_converted() {
+        assert_eq!(
+            sample().with_layout(ChannelLayout::Mono),
+            RawAudioSample::Mono(vec![0.125, 0.125, -0.75, 0.5625])
+        );
+        let constant = RawAudioSample::Mono(vec![0.5; 480]);
+        let resampled = constant.resample(48000, 24000);
+        assert_eq!(resampled.frames(), 240);
+        if let RawAudioSample::Mono(signal) = resampled {
+            assert!(signal[100..140].iter().all(|value| (value - 0.5).abs() < 0.01));
+        }
+        let options = ExportOptions::wav(SampleEncoding::Int16).with_sample_rate(0);
+        assert!(encode(sample(), 8000, &options).is_err());
+    }
+}
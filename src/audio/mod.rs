@@ -2,7 +2,12 @@ use typenum::Same;
 
 use crate::prelude::*;
 
+pub mod chunks;
+pub mod edit;
+pub mod export;
 pub mod node_host;
+pub mod peaks;
+pub mod wav;
 
 #[derive(Clone)]
 pub struct SamplerNode {
@@ -11,13 +16,39 @@ pub struct SamplerNode {
     buffer: Vec<(f32, f32)>
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RawAudioSample {
     Mono(Vec<f32>),
     Stereo(Vec<f32>, Vec<f32>),
 }
 
 impl RawAudioSample {
+    pub fn channels(&self) -> u16 {
+        match self {
+            RawAudioSample::Mono(_) => 1,
+            RawAudioSample::Stereo(..) => 2,
+        }
+    }
+
+    /// Number of samples per channel
+    pub fn frames(&self) -> usize {
+        match self {
+            RawAudioSample::Mono(signal) => signal.len(),
+            RawAudioSample::Stereo(left, right) => std::cmp::min(left.len(), right.len()),
+        }
+    }
+
+    pub fn interleave(&self) -> Vec<f32> {
+        match self {
+            RawAudioSample::Mono(signal) => signal.clone(),
+            RawAudioSample::Stereo(left, right) => left
+                .iter()
+                .zip(right.iter())
+                .flat_map(|(left, right)| [*left, *right])
+                .collect(),
+        }
+    }
+
     pub fn stereoify(self) -> Vec<(f32, f32)> {
         match self {
             RawAudioSample::Mono(signal) => signal.into_iter().map(|sig| (sig, sig)).collect(),
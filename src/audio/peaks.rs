@@ -0,0 +1,397 @@
+use std::time::UNIX_EPOCH;
+
+use crate::prelude::*;
+use tokio_stream::StreamExt;
+
+use super::wav::DecodeStream;
+use super::RawAudioSample;
+
+/// Frames summarized by a peak of the finest level
+pub const BASE_SAMPLES_PER_PEAK: usize = 256;
+/// Ratio between the resolution of two consecutive levels
+pub const LEVEL_FACTOR: usize = 4;
+
+const MAGIC: &[u8; 4] = b"CAPK";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Peak {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
+
+/// Min/max/RMS overview of an audio file at several zoom levels, finest level first
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakPyramid {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub frames: usize,
+    pub levels: Vec<PeakLevel>,
+    /// Source file the pyramid was built from, a cache is stale when it changes
+    source: SourceStamp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakLevel {
+    pub samples_per_peak: usize,
+    /// One vector of peaks per channel
+    pub peaks: Vec<Vec<Peak>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct SourceStamp {
+    size: u64,
+    /// Modification time in nanoseconds since the epoch
+    modified: u64,
+}
+
+/// Running min/max/sum of squares of a bucket
+#[derive(Debug, Clone, Copy)]
+struct Accumulator {
+    min: f32,
+    max: f32,
+    squares: f64,
+    count: usize,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self {
+            min: f32::MAX,
+            max: f32::MIN,
+            squares: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Accumulator {
+    fn push(&mut self, sample: f32) {
+        self.min = f32::min(self.min, sample);
+        self.max = f32::max(self.max, sample);
+        self.squares += (sample as f64) * (sample as f64);
+        self.count += 1;
+    }
+
+    /// Merge a peak summarizing `count` frames
+    fn merge(&mut self, peak: &Peak, count: usize) {
+        self.min = f32::min(self.min, peak.min);
+        self.max = f32::max(self.max, peak.max);
+        self.squares += (peak.rms as f64) * (peak.rms as f64) * count as f64;
+        self.count += count;
+    }
+
+    fn peak(&self) -> Peak {
+        match self.count {
+            0 => Peak::default(),
+            count => Peak {
+                min: self.min,
+                max: self.max,
+                rms: (self.squares / count as f64).sqrt() as f32,
+            },
+        }
+    }
+}
+
+impl SourceStamp {
+    async fn of(path: &Path) -> CaResult<Self> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or_default();
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+impl PeakLevel {
+    /// Summary of `frames` of `channel`, `None` when the range is empty or out of the clip
+    fn summarize(&self, channel: usize, frames: Range<usize>, total_frames: usize) -> Option<Peak> {
+        let peaks = self.peaks.get(channel)?;
+        let end = std::cmp::min(frames.end, total_frames);
+        if frames.start >= end {
+            return None;
+        }
+        let first = frames.start / self.samples_per_peak;
+        let last = std::cmp::min((end - 1) / self.samples_per_peak, peaks.len().checked_sub(1)?);
+        let mut accumulator = Accumulator::default();
+        for (index, peak) in peaks.iter().enumerate().take(last + 1).skip(first) {
+            let start = index * self.samples_per_peak;
+            let count = std::cmp::min(start + self.samples_per_peak, total_frames) - start;
+            accumulator.merge(peak, count);
+        }
+        Some(accumulator.peak())
+    }
+}
+
+impl PeakPyramid {
+    /// Build the pyramid from a decode stream, the file is never fully loaded in memory
+    pub async fn build(path: &Path, mut stream: DecodeStream) -> CaResult<Self> {
+        let source = SourceStamp::of(path).await?;
+        let channels = stream.format.channels as usize;
+        let mut base: Vec<Vec<Peak>> = vec![vec![]; channels];
+        let mut accumulators = vec![Accumulator::default(); channels];
+        let mut frames = 0;
+        while let Some(block) = stream.next().await {
+            let block = block?;
+            let signals: Vec<&[f32]> = match &block.sample {
+                RawAudioSample::Mono(signal) => vec![signal],
+                RawAudioSample::Stereo(left, right) => vec![left, right],
+            };
+            for frame in 0..block.sample.frames() {
+                for (channel, signal) in signals.iter().enumerate() {
+                    accumulators[channel].push(signal[frame]);
+                }
+                frames += 1;
+                if frames % BASE_SAMPLES_PER_PEAK == 0 {
+                    for (channel, accumulator) in accumulators.iter_mut().enumerate() {
+                        base[channel].push(accumulator.peak());
+                        *accumulator = Accumulator::default();
+                    }
+                }
+            }
+        }
+        if frames % BASE_SAMPLES_PER_PEAK != 0 {
+            for (channel, accumulator) in accumulators.iter().enumerate() {
+                base[channel].push(accumulator.peak());
+            }
+        }
+
+        let mut levels = vec![PeakLevel {
+            samples_per_peak: BASE_SAMPLES_PER_PEAK,
+            peaks: base,
+        }];
+        while let Some(previous) = levels.last().filter(|level| level.peaks[0].len() > 1) {
+            let samples_per_peak = previous.samples_per_peak * LEVEL_FACTOR;
+            let count = frames.div_ceil(samples_per_peak);
+            let peaks = (0..channels)
+                .map(|channel| {
+                    (0..count)
+                        .filter_map(|index| {
+                            let start = index * samples_per_peak;
+                            previous.summarize(channel, start..start + samples_per_peak, frames)
+                        })
+                        .collect()
+                })
+                .collect();
+            levels.push(PeakLevel {
+                samples_per_peak,
+                peaks,
+            });
+        }
+
+        Ok(Self {
+            sample_rate: stream.sample_rate,
+            channels: channels as u16,
+            frames,
+            levels,
+            source,
+        })
+    }
+
+    /// One peak per pixel of `pixels` when the whole clip is drawn `width` pixels wide, per channel
+    pub fn peaks_for_pixels(&self, pixels: Range<usize>, width: usize) -> Vec<Vec<Peak>> {
+        self.peaks_for_frames(0..self.frames, pixels, width)
+    }
+
+    /// One peak per pixel of `pixels` when `frames` of the clip are drawn `width` pixels wide, per channel.
+    /// Pixels outside of the clip are omitted.
+    pub fn peaks_for_frames(&self, frames: Range<usize>, pixels: Range<usize>, width: usize) -> Vec<Vec<Peak>> {
+        let visible = frames.end.saturating_sub(frames.start);
+        if width == 0 || visible == 0 {
+            return vec![vec![]; self.channels as usize];
+        }
+        let frames_per_pixel = visible as f64 / width as f64;
+        // coarsest level still finer than a pixel
+        let level = self
+            .levels
+            .iter()
+            .rev()
+            .find(|level| level.samples_per_peak as f64 <= frames_per_pixel)
+            .unwrap_or(&self.levels[0]);
+        (0..self.channels as usize)
+            .map(|channel| {
+                pixels
+                    .clone()
+                    .map_while(|pixel| {
+                        let start = frames.start + (pixel as f64 * frames_per_pixel) as usize;
+                        let end = frames.start + ((pixel + 1) as f64 * frames_per_pixel).ceil() as usize;
+                        level.summarize(channel, start..std::cmp::max(end, start + 1), self.frames)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The pyramid was built from the current content of `path`
+    pub async fn is_fresh(&self, path: &Path) -> bool {
+        SourceStamp::of(path).await.is_ok_and(|source| source == self.source)
+    }
+
+    /// Binary cache layout, little endian: magic, version, source size and modification time,
+    /// sample rate, channels, frames, level count, then every level as samples per peak,
+    /// peak count and the min/max/RMS triples of each channel.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&self.source.size.to_le_bytes());
+        data.extend_from_slice(&self.source.modified.to_le_bytes());
+        data.extend_from_slice(&self.sample_rate.to_le_bytes());
+        data.extend_from_slice(&self.channels.to_le_bytes());
+        data.extend_from_slice(&(self.frames as u64).to_le_bytes());
+        data.extend_from_slice(&(self.levels.len() as u32).to_le_bytes());
+        for level in self.levels.iter() {
+            data.extend_from_slice(&(level.samples_per_peak as u32).to_le_bytes());
+            data.extend_from_slice(&(level.peaks[0].len() as u32).to_le_bytes());
+            for channel in level.peaks.iter() {
+                for peak in channel.iter() {
+                    data.extend_from_slice(&peak.min.to_le_bytes());
+                    data.extend_from_slice(&peak.max.to_le_bytes());
+                    data.extend_from_slice(&peak.rms.to_le_bytes());
+                }
+            }
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> CaResult<Self> {
+        let mut reader = ByteReader { data, offset: 0 };
+        if reader.take(4)? != MAGIC || reader.u32()? != VERSION {
+            return Err(CaError::CoruptedBuffer);
+        }
+        let source = SourceStamp {
+            size: reader.u64()?,
+            modified: reader.u64()?,
+        };
+        let sample_rate = reader.u32()?;
+        let channels = reader.u16()?;
+        let frames = reader.u64()? as usize;
+        let level_count = reader.u32()?;
+        let mut levels = vec![];
+        for _ in 0..level_count {
+            let samples_per_peak = reader.u32()? as usize;
+            let count = reader.u32()? as usize;
+            if samples_per_peak == 0 {
+                return Err(CaError::CoruptedBuffer);
+            }
+            let peaks = (0..channels)
+                .map(|_| {
+                    (0..count)
+                        .map(|_| {
+                            Ok(Peak {
+                                min: reader.f32()?,
+                                max: reader.f32()?,
+                                rms: reader.f32()?,
+                            })
+                        })
+                        .collect::<CaResult<Vec<Peak>>>()
+                })
+                .try_collect()?;
+            levels.push(PeakLevel { samples_per_peak, peaks });
+        }
+        if levels.is_empty() || channels == 0 {
+            return Err(CaError::CoruptedBuffer);
+        }
+        Ok(Self {
+            sample_rate,
+            channels,
+            frames,
+            levels,
+            source,
+        })
+    }
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, count: usize) -> CaResult<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + count)
+            .ok_or(CaError::CoruptedBuffer)?;
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> CaResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().map_err(|_| CaError::CoruptedBuffer)?))
+    }
+
+    fn u32(&mut self) -> CaResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().map_err(|_| CaError::CoruptedBuffer)?))
+    }
+
+    fn u64(&mut self) -> CaResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().map_err(|_| CaError::CoruptedBuffer)?))
+    }
+
+    fn f32(&mut self) -> CaResult<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().map_err(|_| CaError::CoruptedBuffer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::export::{export, ExportOptions, SampleEncoding};
+    use crate::audio::wav::decode_wav_stream;
+
+    async fn pyramid(directory: &TempDir, frames: usize) -> PeakPyramid {
+        let path = directory.path().join("clip.wav");
+        let left = (0..frames).map(|frame| if frame % 2 == 0 { 0.5 } else { -0.5 }).collect();
+        let right = vec![0.25; frames];
+        let sample = RawAudioSample::Stereo(left, right);
+        export(sample, 1000, &path, &ExportOptions::wav(SampleEncoding::Float32)).await.unwrap();
+        PeakPyramid::build(&path, decode_wav_stream(&path, 300).await.unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn levels_summarize_the_whole_clip() {
+        let directory = TempDir::new("crovai_peaks").unwrap();
+        let pyramid = pyramid(&directory, 5000).await;
+        assert_eq!(pyramid.frames, 5000);
+        assert_eq!(pyramid.channels, 2);
+        let sizes: Vec<(usize, usize)> = pyramid
+            .levels
+            .iter()
+            .map(|level| (level.samples_per_peak, level.peaks[0].len()))
+            .collect();
+        assert_eq!(sizes, [(256, 20), (1024, 5), (4096, 2), (16384, 1)]);
+        let whole = pyramid.levels.last().unwrap();
+        assert_eq!(whole.peaks[0][0], Peak { min: -0.5, max: 0.5, rms: 0.5 });
+        assert_eq!(whole.peaks[1][0], Peak { min: 0.25, max: 0.25, rms: 0.25 });
+    }
+
+    #[tokio::test]
+    async fn pixels_past_the_clip_are_omitted() {
+        let directory = TempDir::new("crovai_peaks").unwrap();
+        let pyramid = pyramid(&directory, 5000).await;
+        let peaks = pyramid.peaks_for_pixels(0..10, 10);
+        assert_eq!(peaks[0].len(), 10);
+        let peaks = pyramid.peaks_for_frames(4000..6000, 0..4, 4);
+        assert_eq!(peaks[1].len(), 2);
+        assert!(pyramid.peaks_for_pixels(0..10, 0)[0].is_empty());
+    }
+
+    #[tokio::test]
+    async fn encoded_pyramids_roundtrip() {
+        let directory = TempDir::new("crovai_peaks").unwrap();
+        let pyramid = pyramid(&directory, 3000).await;
+        let encoded = pyramid.encode();
+        assert_eq!(PeakPyramid::decode(&encoded).unwrap(), pyramid);
+        assert!(pyramid.is_fresh(&directory.path().join("clip.wav")).await);
+        assert!(PeakPyramid::decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut corrupted = encoded.clone();
+        corrupted[0] = b'X';
+        assert!(PeakPyramid::decode(&corrupted).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::prelude::*;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+
+use super::RawAudioSample;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SampleFormat {
+    Int,
+    Float,
+}
+
+/// Storage format of a decoded file
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub sample_format: SampleFormat,
+    pub bits_per_sample: u16,
+    pub channels: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedAudio {
+    pub sample: RawAudioSample,
+    pub sample_rate: u32,
+    pub format: AudioFormat,
+}
+
+/// Fixed size block yielded by `DecodeStream`, the last block may be shorter
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioBlock {
+    pub sample: RawAudioSample,
+    /// Position of the first frame of the block in the file
+    pub offset: usize,
+    pub progress: DecodeProgress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeProgress {
+    pub decoded_frames: usize,
+    pub total_frames: usize,
+}
+
+/// Stream of decoded blocks, decoding runs on the blocking thread pool.
+/// Dropping the stream or cancelling its token stops the decoding, a cancelled stream
+/// yields `CaError::Canceled` once then ends.
+#[derive(Debug)]
+pub struct DecodeStream {
+    pub sample_rate: u32,
+    pub format: AudioFormat,
+    pub total_frames: usize,
+    blocks: ReceiverStream<CaResult<AudioBlock>>,
+    cancellation: CancellationToken,
+    canceled: bool,
+}
+
+impl DecodeProgress {
+    pub fn ratio(&self) -> f32 {
+        match self.total_frames {
+            0 => 1.0,
+            total => self.decoded_frames as f32 / total as f32,
+        }
+    }
+}
+
+impl DecodeStream {
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+}
+
+impl tokio_stream::Stream for DecodeStream {
+    type Item = CaResult<AudioBlock>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.cancellation.is_cancelled() {
+            let reported = std::mem::replace(&mut self.canceled, true);
+            return Poll::Ready((!reported).then_some(Err(CaError::Canceled)));
+        }
+        Pin::new(&mut self.blocks).poll_next(cx)
+    }
+}
+
+impl Drop for DecodeStream {
+    fn drop(&mut self) {
+        self.cancellation.cancel();
+    }
+}
+
+impl DecodedAudio {
+    pub fn duration(&self) -> f64 {
+        self.sample.frames() as f64 / self.sample_rate as f64
+    }
+}
+
+impl AudioFormat {
+    /// Supported formats: 8/16/24/32 bits integer and 32 bits float, mono or stereo
+    pub fn from_spec(spec: hound::WavSpec) -> CaResult<Self> {
+        let sample_format = match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Int, 8 | 16 | 24 | 32) => SampleFormat::Int,
+            (hound::SampleFormat::Float, 32) => SampleFormat::Float,
+            (format, bits) => {
+                return Err(CaError::UnsupportedFormat(format!("{:?} {} bits", format, bits)))
+            }
+        };
+        if !(1..=2).contains(&spec.channels) {
+            return Err(CaError::UnsupportedFormat(format!("{} channels", spec.channels)));
+        }
+        Ok(Self {
+            sample_format,
+            bits_per_sample: spec.bits_per_sample,
+            channels: spec.channels,
+        })
+    }
+}
+
+/* IMPORTANT: this is not using async I/O */
+pub fn decode_wav_file(path: &Path) -> CaResult<DecodedAudio> {
+    decode_wav(hound::WavReader::open(path)?)
+}
+
+/// Decode the whole file on the blocking thread pool
+pub async fn decode_wav_file_async(path: &Path) -> CaResult<DecodedAudio> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || decode_wav_file(&path))
+        .await?
+}
+
+/// Decode the file in blocks of `block_frames` frames, see `DecodeStream`
+pub async fn decode_wav_stream(path: &Path, block_frames: usize) -> CaResult<DecodeStream> {
+    let path = path.to_owned();
+    let reader = tokio::task::spawn_blocking(move || hound::WavReader::open(path))
+        .await??;
+    let spec = reader.spec();
+    let format = AudioFormat::from_spec(spec)?;
+    let total_frames = reader.duration() as usize;
+    let block_frames = std::cmp::max(block_frames, 1);
+
+    // a couple of blocks in flight is enough to keep the consumer busy
+    let (sender, receiver) = tokio::sync::mpsc::channel(2);
+    let cancellation = CancellationToken::new();
+    let token = cancellation.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut reader = reader;
+        let mut offset = 0;
+        while offset < total_frames && !token.is_cancelled() {
+            let block = read_interleaved(&mut reader, format, block_frames).map(|interleaved| {
+                let sample = deinterleave(&interleaved, format.channels);
+                let frames = sample.frames();
+                let block = AudioBlock {
+                    sample,
+                    offset,
+                    progress: DecodeProgress {
+                        decoded_frames: offset + frames,
+                        total_frames,
+                    },
+                };
+                offset += frames;
+                block
+            });
+            let failed = block.is_err();
+            if sender.blocking_send(block).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Ok(DecodeStream {
+        sample_rate: spec.sample_rate,
+        format,
+        total_frames,
+        blocks: ReceiverStream::new(receiver),
+        cancellation,
+        canceled: false,
+    })
+}
+
+pub fn decode_wav<R: Read>(mut reader: hound::WavReader<R>) -> CaResult<DecodedAudio> {
+    let spec = reader.spec();
+    let format = AudioFormat::from_spec(spec)?;
+    let interleaved = read_interleaved(&mut reader, format, usize::MAX)?;
+    Ok(DecodedAudio {
+        sample: deinterleave(&interleaved, format.channels),
+        sample_rate: spec.sample_rate,
+        format,
+    })
+}
+
+/// Read up to `max_frames` frames as interleaved samples normalized to [-1, 1]
+pub fn read_interleaved<R: Read>(
+    reader: &mut hound::WavReader<R>,
+    format: AudioFormat,
+    max_frames: usize,
+) -> CaResult<Vec<f32>> {
+    let max_samples = max_frames.saturating_mul(format.channels as usize);
+    match format.sample_format {
+        SampleFormat::Float => Ok(reader.samples::<f32>().take(max_samples).try_collect()?),
+        SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (format.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .take(max_samples)
+                .map(|sample| Ok(sample? as f32 * scale))
+                .collect()
+        }
+    }
+}
+
+pub fn deinterleave(interleaved: &[f32], channels: u16) -> RawAudioSample {
+    match channels {
+        1 => RawAudioSample::Mono(interleaved.to_vec()),
+        _ => {
+            let (left, right) = interleaved
+                .chunks_exact(channels as usize)
+                .map(|frame| (frame[0], frame[1]))
+                .unzip();
+            RawAudioSample::Stereo(left, right)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::export::{export, ExportOptions, SampleEncoding};
+    use tokio_stream::StreamExt;
+
+    async fn clip(directory: &TempDir, frames: usize) -> PathBuf {
+        let path = directory.path().join("clip.wav");
+        let signal = (0..frames).map(|frame| (frame % 100) as f32 / 100.0).collect();
+        export(RawAudioSample::Mono(signal), 1000, &path, &ExportOptions::wav(SampleEncoding::Float32))
+            .await
+            .unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn streamed_blocks_cover_the_file() {
+        let directory = TempDir::new("crovai_wav").unwrap();
+        let path = clip(&directory, 1050).await;
+        let mut stream = decode_wav_stream(&path, 100).await.unwrap();
+        assert_eq!(stream.total_frames, 1050);
+        let mut blocks = vec![];
+        while let Some(block) = stream.next().await {
+            blocks.push(block.unwrap());
+        }
+        assert_eq!(blocks.len(), 11);
+        assert!(blocks.iter().enumerate().all(|(index, block)| block.offset == index * 100));
+        let last = blocks.last().unwrap();
+        assert_eq!(last.sample.frames(), 50);
+        assert_eq!(last.progress.decoded_frames, last.progress.total_frames);
+        assert_eq!(last.progress.ratio(), 1.0);
+        let decoded = decode_wav_file_async(&path).await.unwrap();
+        let streamed: Vec<f32> = blocks.iter().flat_map(|block| block.sample.interleave()).collect();
+        assert_eq!(streamed, decoded.sample.interleave());
+    }
+
+    #[tokio::test]
+    async fn cancelled_streams_stop_decoding() {
+        let directory = TempDir::new("crovai_wav").unwrap();
+        let path = clip(&directory, 10_000).await;
+        let mut stream = decode_wav_stream(&path, 10).await.unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        stream.cancel();
+        assert!(matches!(stream.next().await, Some(Err(CaError::Canceled))));
+        assert!(stream.next().await.is_none());
+
+        let stream = decode_wav_stream(&path, 10).await.unwrap();
+        let token = stream.cancellation_token();
+        drop(stream);
+        assert!(token.is_cancelled());
+    }
+
+    fn encoded<S: hound::Sample + Copy>(spec: hound::WavSpec, samples: &[S]) -> Vec<u8> {
+        let mut cursor = std::io::Cursor::new(vec![]);
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        for sample in samples {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        cursor.into_inner()
+    }
+
+    fn decoded(data: Vec<u8>) -> CaResult<DecodedAudio> {
+        decode_wav(hound::WavReader::new(std::io::Cursor::new(data))?)
+    }
+
+    fn spec(channels: u16, bits_per_sample: u16, sample_format: hound::SampleFormat) -> hound::WavSpec {
+        hound::WavSpec {
+            channels,
+            sample_rate: 8000,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+
+    #[test]
+    fn integer_formats_are_normalized() {
+        let eight = encoded(spec(1, 8, hound::SampleFormat::Int), &[-128i8, -64, 0, 64, 127]);
+        let decoded_eight = decoded(eight).unwrap();
+        assert_eq!(decoded_eight.sample, RawAudioSample::Mono(vec![-1.0, -0.5, 0.0, 0.5, 127.0 / 128.0]));
+        assert_eq!(decoded_eight.format.bits_per_sample, 8);
+
+        let thirty_two = encoded(spec(2, 32, hound::SampleFormat::Int), &[i32::MIN, 1 << 30, 0, -(1 << 30)]);
+        let decoded_thirty_two = decoded(thirty_two).unwrap();
+        assert_eq!(decoded_thirty_two.sample, RawAudioSample::Stereo(vec![-1.0, 0.0], vec![0.5, -0.5]));
+        assert_eq!(decoded_thirty_two.format.sample_format, SampleFormat::Int);
+        assert_eq!(decoded_thirty_two.duration(), 2.0 / 8000.0);
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected() {
+        let three_channels = encoded(spec(3, 16, hound::SampleFormat::Int), &[0i16; 6]);
+        assert!(matches!(decoded(three_channels), Err(CaError::UnsupportedFormat(_))));
+        let twelve_bits = spec(1, 12, hound::SampleFormat::Int);
+        assert!(AudioFormat::from_spec(twelve_bits).is_err());
+    }
+}
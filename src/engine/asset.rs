@@ -1,6 +1,15 @@
 
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use crate::prelude::*;
 
+use super::diffusion::DiffusionModelTemplate;
+use crate::audio::chunks::{read_wav_metadata, WavMetadata};
+use crate::audio::edit::EditOperation;
+use crate::audio::peaks::PeakPyramid;
+use crate::audio::export::{export, ExportOptions};
+use crate::audio::wav::{decode_wav_file_async, decode_wav_stream, DecodeStream, DecodedAudio};
+
 #[derive(Debug, Clone)]
 pub enum Asset {
     Tmp {
@@ -12,6 +21,32 @@ pub enum Asset {
     }
 }
 
+/// Generation record persisted next to the asset file as `<file>.json`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct AssetMetadata {
+    pub model: Option<String>,
+    /// Prompt sent to the model, after host variables expansion
+    pub prompt: Option<String>,
+    /// Prompt as written by the user when it contained host variables
+    pub prompt_template: Option<String>,
+    pub seed: Option<u64>,
+    /// Template sent to the worker, secrets redacted
+    pub template: Option<DiffusionModelTemplate>,
+    pub started_at: Option<DateTime<Local>>,
+    pub finished_at: Option<DateTime<Local>>,
+    /// Wall clock duration of the generation in seconds
+    pub generation_time: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    /// Duration of the audio in seconds
+    pub duration: Option<f64>,
+    /// Asset this one was edited from
+    pub parent: Option<PathBuf>,
+    /// Edits applied since the generation, oldest first
+    pub operations: Vec<EditOperation>,
+}
+
 impl PartialEq for Asset {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -28,6 +63,46 @@ impl PartialEq for Asset {
     }
 }
 
+impl AssetMetadata {
+    /// Provenance of an asset generated from `template`, the template is stored redacted
+    pub fn from_template(template: &DiffusionModelTemplate) -> Self {
+        let string = |key: &str| template.options.get(key).and_then(|opt| opt.as_string_value());
+        Self {
+            model: string("MODEL"),
+            prompt: string("CONDITION_PROMPT"),
+            seed: template.options.get("SEED").and_then(|opt| opt.as_seed_value()),
+            template: Some(template.redacted()),
+            ..Default::default()
+        }
+    }
+
+    /// Copy of `options` with an empty `bext` description replaced by the prompt
+    pub fn describe(&self, options: &ExportOptions) -> ExportOptions {
+        let mut options = options.clone();
+        let bext = options.wav_metadata.as_mut().and_then(|metadata| metadata.bext.as_mut());
+        if let (Some(bext), Some(prompt)) = (bext, self.prompt.as_ref()) {
+            if bext.description.is_empty() {
+                bext.description = prompt.clone();
+            }
+        }
+        options
+    }
+
+    /// Fill the audio format fields from the WAV header of `path`, read on the blocking thread pool
+    pub async fn read_format(&mut self, path: &Path) -> CaResult<()> {
+        let path = path.to_owned();
+        let (spec, frames) = tokio::task::spawn_blocking(move || {
+            let reader = hound::WavReader::open(path)?;
+            CaResult::Ok((reader.spec(), reader.duration()))
+        })
+        .await??;
+        self.sample_rate = Some(spec.sample_rate);
+        self.channels = Some(spec.channels);
+        self.duration = Some(frames as f64 / spec.sample_rate as f64);
+        Ok(())
+    }
+}
+
 impl Asset {
 
     pub fn load_naive(path: PathBuf) -> Asset {
@@ -41,24 +116,212 @@ impl Asset {
         }
     }
 
+    /// Timestamped file name, unique within the process even when several are created in the same millisecond
+    pub fn unique_file_name(extension: &str) -> String {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let fname = Local::now().format("%Y-%m-%d_%H-%M-%S_%3f");
+        let index = COUNTER.fetch_add(1, Ordering::Relaxed);
+        format!("{}_{}.{}", fname, index, extension)
+    }
+
+    /// New file name in `directory`, unique within the process even for assets created in the same instant
     pub async fn create_tmp(extension: &str, directory: Arc<RwLock<TempDir>>) -> Self {
-        let fname = Local::now().format("%Y-%m-%d_%H-%M-%S");
-        let path = directory.read().await.path().join(format!("{}.{}", fname, extension));
+        let path = directory.read().await.path().join(Self::unique_file_name(extension));
         Self::Tmp {
             path,
             directory,
         }
     }
 
+    pub fn sidecar_path(&self) -> PathBuf {
+        let mut path = self.path().as_os_str().to_owned();
+        path.push(".json");
+        PathBuf::from(path)
+    }
+
+    /// Waveform overview cache, see `peaks`
+    pub fn peaks_path(&self) -> PathBuf {
+        let mut path = self.path().as_os_str().to_owned();
+        path.push(".peaks");
+        PathBuf::from(path)
+    }
+
+    /// Files derived from the asset, they follow it when it is moved or deleted
+    pub fn companion_paths(&self) -> Vec<PathBuf> {
+        vec![self.sidecar_path(), self.peaks_path()]
+    }
+
+    pub async fn write_metadata(&self, metadata: &AssetMetadata) -> CaResult<()> {
+        tokio::fs::write(self.sidecar_path(), serde_json::to_vec_pretty(metadata)?).await?;
+        Ok(())
+    }
+
+    /// Load the metadata sidecar, `None` for assets without one
+    pub async fn metadata(&self) -> CaResult<Option<AssetMetadata>> {
+        match tokio::fs::read(self.sidecar_path()).await {
+            Ok(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub async fn copy_to(&self, path: &Path) -> CaResult<Asset> {
         tokio::fs::copy(self.path(), path).await?;
         Ok(Asset::Stored { path: path.to_owned() })
     }
 
-    /* IMPORTANT: this is not using async I/O and will cause issues */
+    /// Interleaved samples normalized to [-1, 1], see `decode` to get the channels and sample rate
     pub async fn get_samples(&self) -> CaResult<Vec<f32>> {
-        let mut reader = hound::WavReader::open(self.path())?;
-        let samples: Vec<i16> = reader.samples::<i16>().try_collect()?;
-        Ok(samples.into_iter().map(|s| s as f32 / i16::MAX as f32).collect())
+        Ok(self.decode().await?.sample.interleave())
     }
-}
\ No newline at end of file
+
+    /// Decode a WAV asset (8/16/24/32 bits integer or 32 bits float, mono or stereo) off the runtime threads
+    pub async fn decode(&self) -> CaResult<DecodedAudio> {
+        decode_wav_file_async(self.path()).await
+    }
+
+    /// Waveform overview, read from `<file>.peaks` or built and cached there when missing or stale
+    pub async fn peaks(&self) -> CaResult<PeakPyramid> {
+        match tokio::fs::read(self.peaks_path()).await {
+            Ok(raw) => match PeakPyramid::decode(&raw) {
+                Ok(peaks) if peaks.is_fresh(self.path()).await => return Ok(peaks),
+                Ok(_) => debug!(path = ?self.path(), "stale peak cache"),
+                Err(e) => warn!(path = ?self.peaks_path(), "invalid peak cache: {}", e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let peaks = PeakPyramid::build(self.path(), self.decode_stream(1 << 16).await?).await?;
+        if let Err(e) = tokio::fs::write(self.peaks_path(), peaks.encode()).await {
+            warn!(path = ?self.peaks_path(), "could not cache peaks: {}", e);
+        }
+        Ok(peaks)
+    }
+
+    /// Decode the asset as a stream of `block_frames` frames blocks, for large files
+    pub async fn decode_stream(&self, block_frames: usize) -> CaResult<DecodeStream> {
+        decode_wav_stream(self.path(), block_frames).await
+    }
+
+    /// Apply `operations` in order and write the result to `path`, the asset itself is left untouched.
+    /// The new asset inherits the metadata of this one, with this asset as parent and the operations appended.
+    /// Loop and marker positions of `options` refer to this asset, they are moved along with the audio.
+    pub async fn edit(&self, operations: &[EditOperation], path: &Path, options: &ExportOptions) -> CaResult<Asset> {
+        let decoded = self.decode().await?;
+        let sample_rate = decoded.sample_rate;
+        let ops = operations.to_vec();
+        let mut options = options.clone();
+        let wav_metadata = options.wav_metadata.take();
+        let (sample, wav_metadata) = tokio::task::spawn_blocking(move || {
+            ops.iter().try_fold((decoded.sample, wav_metadata), |(sample, wav_metadata), operation| {
+                let wav_metadata = wav_metadata
+                    .map(|wav_metadata| operation.remap(&wav_metadata, &sample, sample_rate))
+                    .transpose()?;
+                Ok::<_, CaError>((operation.apply(&sample, sample_rate)?, wav_metadata))
+            })
+        })
+        .await??;
+        options.wav_metadata = wav_metadata;
+
+        let mut metadata = self.metadata().await?.unwrap_or_default();
+        let edited = export(sample, sample_rate, path, &metadata.describe(&options)).await?;
+        metadata.parent = Some(self.path().to_owned());
+        metadata.operations.extend_from_slice(operations);
+        if let Err(e) = metadata.read_format(edited.path()).await {
+            // raw PCM and AIFF exports have no WAV header to read the format from
+            debug!(path = ?edited.path(), "edited asset format not recorded: {}", e);
+            metadata.sample_rate = None;
+            metadata.channels = None;
+            metadata.duration = None;
+        }
+        edited.write_metadata(&metadata).await?;
+        Ok(edited)
+    }
+
+    /// Loops, markers, tempo and description chunks embedded in a WAV asset
+    pub async fn wav_metadata(&self) -> CaResult<WavMetadata> {
+        let path = self.path().to_owned();
+        tokio::task::spawn_blocking(move || read_wav_metadata(&path))
+            .await?
+    }
+
+    /// Decode the asset and write it to `path` in the format described by `options`.
+    /// An empty `bext` description is filled with the prompt of the asset.
+    pub async fn export(&self, path: &Path, options: &ExportOptions) -> CaResult<Asset> {
+        let options = self.metadata().await?.unwrap_or_default().describe(options);
+        let decoded = self.decode().await?;
+        export(decoded.sample, decoded.sample_rate, path, &options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::chunks::{BroadcastInfo, SampleLoop};
+    use crate::audio::export::SampleEncoding;
+    use crate::audio::RawAudioSample;
+
+    #[tokio::test]
+    async fn read_format_fills_the_format_fields() {
+        let directory = TempDir::new("crovai_asset").unwrap();
+        let path = directory.path().join("clip.wav");
+        let sample = RawAudioSample::Stereo(vec![0.0; 22050], vec![0.0; 22050]);
+        export(sample, 44100, &path, &ExportOptions::wav(SampleEncoding::Int24)).await.unwrap();
+        let mut metadata = AssetMetadata::default();
+        metadata.read_format(&path).await.unwrap();
+        assert_eq!(metadata.sample_rate, Some(44100));
+        assert_eq!(metadata.channels, Some(2));
+        assert_eq!(metadata.duration, Some(0.5));
+    }
+
+    #[tokio::test]
+    async fn exports_are_described_with_the_prompt() {
+        let directory = TempDir::new("crovai_asset").unwrap();
+        let path = directory.path().join("clip.wav");
+        let asset = export(RawAudioSample::Mono(vec![0.0; 100]), 1000, &path, &ExportOptions::default())
+            .await
+            .unwrap();
+        let metadata = AssetMetadata {
+            prompt: Some("warm pads".to_string()),
+            ..Default::default()
+        };
+        asset.write_metadata(&metadata).await.unwrap();
+
+        let wav_metadata = WavMetadata {
+            bext: Some(BroadcastInfo::describe("", "crovai")),
+            ..Default::default()
+        };
+        let options = ExportOptions::default().with_wav_metadata(wav_metadata);
+        let exported = asset.export(&directory.path().join("export.wav"), &options).await.unwrap();
+        let bext = exported.wav_metadata().await.unwrap().bext.unwrap();
+        assert_eq!(bext.description, "warm pads");
+        assert_eq!(bext.originator, "crovai");
+    }
+
+    #[tokio::test]
+    async fn edits_move_loops_along_with_the_audio() {
+        let directory = TempDir::new("crovai_asset").unwrap();
+        let path = directory.path().join("clip.wav");
+        let asset = export(RawAudioSample::Mono(vec![0.5; 1000]), 1000, &path, &ExportOptions::default())
+            .await
+            .unwrap();
+        let wav_metadata = WavMetadata {
+            loops: vec![SampleLoop {
+                range: 600..900,
+                kind: Default::default(),
+                play_count: 0,
+            }],
+            ..Default::default()
+        };
+        let options = ExportOptions::default().with_wav_metadata(wav_metadata);
+        let operations = [EditOperation::Slice { start: 0.5, end: 1.0 }, EditOperation::Reverse];
+        let edited = asset
+            .edit(&operations, &directory.path().join("edited.wav"), &options)
+            .await
+            .unwrap();
+        assert_eq!(edited.wav_metadata().await.unwrap().loops[0].range, 100..400);
+        let metadata = edited.metadata().await.unwrap().unwrap();
+        assert_eq!(metadata.operations, operations);
+        assert_eq!(metadata.duration, Some(0.5));
+    }
+}
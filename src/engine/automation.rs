@@ -0,0 +1,289 @@
+use crate::prelude::*;
+
+use super::diffusion::{DiffusionModelOpt, DiffusionModelTemplate, EnumChoice, EnumOpt, FloatOpt, IntOpt};
+
+/// Host (DAW) facing view of the visible numeric and enum options of a template.
+/// Parameter ids are derived from the option key so they are stable across sessions and model reloads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostParameterMap {
+    parameters: Vec<HostParameter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostParameter {
+    /// Stable 31 bits identifier (VST3 reserves the upper half of the id space)
+    pub id: u32,
+    pub key: String,
+    pub label: String,
+    pub unit: Option<String>,
+    pub kind: HostParameterKind,
+    pub scale: ParameterScale,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostParameterKind {
+    Int { min: i64, max: i64 },
+    Float { min: f32, max: f32 },
+    Enum { choices: Vec<EnumChoice> },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum ParameterScale {
+    #[default]
+    Linear,
+    /// Only applied to strictly positive ranges, falls back to linear otherwise
+    Logarithmic,
+}
+
+/// Value of a parameter in the template domain
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostParameterValue {
+    Int(i64),
+    Float(f32),
+    Enum(String),
+}
+
+/// FNV-1a hash of the option key truncated to 31 bits
+pub fn parameter_id(key: &str) -> u32 {
+    let hash = key.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    hash & 0x7fff_ffff
+}
+
+impl HostParameterMap {
+    /// Map every visible Int/Float option with a range and every visible Enum option,
+    /// parameters are ordered by metadata group/order then by key.
+    /// Visibility includes the `VisibleIf` rules, the map must be rebuilt when the values they test change.
+    pub fn from_template(template: &DiffusionModelTemplate) -> Self {
+        let mut options: Vec<(&String, &DiffusionModelOpt)> = template
+            .options
+            .iter()
+            .filter(|(key, _)| template.is_visible(key))
+            .collect();
+        options.sort_by_key(|(key, opt)| {
+            let meta = opt.meta();
+            (
+                meta.and_then(|meta| meta.group.clone()),
+                meta.and_then(|meta| meta.order),
+                (*key).clone(),
+            )
+        });
+        let parameters = options
+            .into_iter()
+            .filter_map(|(key, opt)| {
+                let kind = match opt {
+                    DiffusionModelOpt::Int(IntOpt { range: Some(range), .. }) if range.end > range.start => {
+                        HostParameterKind::Int { min: range.start, max: range.end }
+                    }
+                    DiffusionModelOpt::Float(FloatOpt { range: Some(range), .. }) if range.end > range.start => {
+                        HostParameterKind::Float { min: range.start, max: range.end }
+                    }
+                    DiffusionModelOpt::Enum(EnumOpt { choices, .. }) if !choices.is_empty() => {
+                        HostParameterKind::Enum { choices: choices.clone() }
+                    }
+                    _ => return None,
+                };
+                let meta = opt.meta();
+                Some(HostParameter {
+                    id: parameter_id(key),
+                    key: key.clone(),
+                    label: meta.and_then(|meta| meta.label.clone()).unwrap_or_else(|| key.clone()),
+                    unit: meta.and_then(|meta| meta.unit.clone()),
+                    kind,
+                    scale: ParameterScale::Linear,
+                })
+            })
+            .collect();
+        Self { parameters }
+    }
+
+    /// Override the scale of the parameter mapped to `key`
+    pub fn with_scale(mut self, key: &str, scale: ParameterScale) -> Self {
+        if let Some(parameter) = self.parameters.iter_mut().find(|parameter| parameter.key == key) {
+            parameter.scale = scale;
+        }
+        self
+    }
+
+    pub fn parameters(&self) -> &[HostParameter] {
+        &self.parameters
+    }
+
+    pub fn get(&self, id: u32) -> Option<&HostParameter> {
+        self.parameters.iter().find(|parameter| parameter.id == id)
+    }
+
+    /// Current normalized value of a parameter, `None` if the option has no value
+    pub fn normalized_value(&self, template: &DiffusionModelTemplate, id: u32) -> Option<f32> {
+        let parameter = self.get(id)?;
+        let value = parameter.read(template.options.get(&parameter.key)?)?;
+        Some(parameter.normalize(&value))
+    }
+
+    /// Write the denormalized value of a host parameter change into the template
+    pub fn set_normalized(&self, template: &mut DiffusionModelTemplate, id: u32, normalized: f32) -> CaResult<()> {
+        let parameter = self.get(id).ok_or(CaError::UnknownParameter(id))?;
+        let opt = template
+            .options
+            .get_mut(&parameter.key)
+            .ok_or(CaError::UnknownParameter(id))?;
+        match (opt, parameter.denormalize(normalized)) {
+            (DiffusionModelOpt::Int(opt), HostParameterValue::Int(value)) => opt.value = Some(value),
+            (DiffusionModelOpt::Float(opt), HostParameterValue::Float(value)) => opt.value = Some(value),
+            (DiffusionModelOpt::Enum(opt), HostParameterValue::Enum(value)) => opt.value = Some(value),
+            _ => return Err(CaError::UnknownParameter(id)),
+        }
+        Ok(())
+    }
+}
+
+impl HostParameter {
+    /// Number of discrete steps (VST3 `stepCount`), 0 for continuous parameters
+    pub fn step_count(&self) -> usize {
+        match &self.kind {
+            HostParameterKind::Int { min, max } => (max - min) as usize,
+            HostParameterKind::Float { .. } => 0,
+            HostParameterKind::Enum { choices } => choices.len() - 1,
+        }
+    }
+
+    pub fn normalize(&self, value: &HostParameterValue) -> f32 {
+        let normalized = match (&self.kind, value) {
+            (HostParameterKind::Int { min, max }, HostParameterValue::Int(value)) => {
+                self.unit_from_value(*value as f64, *min as f64, *max as f64)
+            }
+            (HostParameterKind::Float { min, max }, HostParameterValue::Float(value)) => {
+                self.unit_from_value(*value as f64, *min as f64, *max as f64)
+            }
+            (HostParameterKind::Enum { choices }, HostParameterValue::Enum(value)) => {
+                let index = choices.iter().position(|choice| &choice.value == value).unwrap_or(0);
+                match choices.len() {
+                    1 => 0.0,
+                    len => index as f64 / (len - 1) as f64,
+                }
+            }
+            _ => 0.0,
+        };
+        normalized.clamp(0.0, 1.0) as f32
+    }
+
+    pub fn denormalize(&self, normalized: f32) -> HostParameterValue {
+        let normalized = (normalized as f64).clamp(0.0, 1.0);
+        match &self.kind {
+            HostParameterKind::Int { min, max } => {
+                HostParameterValue::Int(self.value_from_unit(normalized, *min as f64, *max as f64).round() as i64)
+            }
+            HostParameterKind::Float { min, max } => {
+                HostParameterValue::Float(self.value_from_unit(normalized, *min as f64, *max as f64) as f32)
+            }
+            HostParameterKind::Enum { choices } => {
+                let index = (normalized * (choices.len() - 1) as f64).round() as usize;
+                HostParameterValue::Enum(choices[index].value.clone())
+            }
+        }
+    }
+
+    /// Display string of a normalized value, with its unit
+    pub fn format(&self, normalized: f32) -> String {
+        let value = match self.denormalize(normalized) {
+            HostParameterValue::Int(value) => format!("{}", value),
+            HostParameterValue::Float(value) => format!("{:.2}", value),
+            HostParameterValue::Enum(value) => {
+                return match &self.kind {
+                    HostParameterKind::Enum { choices } => choices
+                        .iter()
+                        .find(|choice| choice.value == value)
+                        .map(|choice| choice.label.clone())
+                        .unwrap_or(value),
+                    _ => value,
+                }
+            }
+        };
+        match self.unit.as_ref() {
+            Some(unit) => format!("{} {}", value, unit),
+            None => value,
+        }
+    }
+
+    fn read(&self, opt: &DiffusionModelOpt) -> Option<HostParameterValue> {
+        match opt {
+            DiffusionModelOpt::Int(IntOpt { value, .. }) => value.map(HostParameterValue::Int),
+            DiffusionModelOpt::Float(FloatOpt { value, .. }) => value.map(HostParameterValue::Float),
+            DiffusionModelOpt::Enum(EnumOpt { value, .. }) => value.clone().map(HostParameterValue::Enum),
+            _ => None,
+        }
+    }
+
+    fn is_logarithmic(&self, min: f64) -> bool {
+        self.scale == ParameterScale::Logarithmic && min > 0.0
+    }
+
+    fn unit_from_value(&self, value: f64, min: f64, max: f64) -> f64 {
+        if self.is_logarithmic(min) {
+            (value.max(min).ln() - min.ln()) / (max.ln() - min.ln())
+        } else {
+            (value - min) / (max - min)
+        }
+    }
+
+    fn value_from_unit(&self, normalized: f64, min: f64, max: f64) -> f64 {
+        if self.is_logarithmic(min) {
+            (min.ln() + normalized * (max.ln() - min.ln())).exp()
+        } else {
+            min + normalized * (max - min)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(mode: &str) -> DiffusionModelTemplate {
+        serde_json::from_value(serde_json::json!({
+            "options": {
+                "MODE": {
+                    "kind": "Enum",
+                    "value": mode,
+                    "choices": [
+                        { "value": "fast", "label": "Fast" },
+                        { "value": "custom", "label": "Custom" }
+                    ]
+                },
+                "STEPS": { "kind": "Int", "value": 50, "range": { "start": 1, "end": 200 } },
+                "SEED": { "kind": "Seed", "value": null, "random": true },
+                "POINT": { "kind": "Float", "hidden": true, "value": 1, "range": { "start": 0, "end": 2 } }
+            },
+            "rules": [
+                {
+                    "rule": "VisibleIf",
+                    "option": "STEPS",
+                    "condition": { "test": "Equals", "option": "MODE", "value": "custom" }
+                }
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn keys(map: &HostParameterMap) -> Vec<&str> {
+        map.parameters().iter().map(|parameter| parameter.key.as_str()).collect()
+    }
+
+    #[test]
+    fn parameters_follow_visibility_rules() {
+        assert_eq!(keys(&HostParameterMap::from_template(&template("fast"))), ["MODE"]);
+        assert_eq!(keys(&HostParameterMap::from_template(&template("custom"))), ["MODE", "STEPS"]);
+    }
+
+    #[test]
+    fn normalized_values_roundtrip() {
+        let mut template = template("custom");
+        let map = HostParameterMap::from_template(&template);
+        let steps = parameter_id("STEPS");
+        map.set_normalized(&mut template, steps, 1.0).unwrap();
+        assert_eq!(template.options["STEPS"].as_int_ref().unwrap().value, Some(200));
+        assert_eq!(map.normalized_value(&template, steps), Some(1.0));
+        assert_eq!(map.get(parameter_id("MODE")).unwrap().format(1.0), "Custom");
+    }
+}
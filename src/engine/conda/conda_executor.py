@@ -5,89 +5,265 @@ import socket
 import struct
 import time
 import re
+import copy
+import uuid
 
+import torch
 import torchaudio
-from audiocraft.models import MusicGen
+from audiocraft.models import MusicGen, AudioGen
 from audiocraft.data.audio import audio_write
 
 
 sock = None
+mbd = {}
 
 difussion_models = {
     "MusicGenSmall": {
         "options": {
             "MODEL": { "hidden": True, "kind": "String", "value": "MusicGenSmall" },
             "POINT": { "hidden": True, "kind": "String", "value": "facebook/musicgen-small" },
-            "CONDITION_PROMPT": { "kind": "String", "value": "140bpm dark techno hypnotic drums loop" },
+            "CONDITION_PROMPT": { "kind": "String", "required": True, "value": "140bpm dark techno hypnotic drums loop" },
             "SECONDS_TOTAL": { "kind": "Float", "value": 20, "range": { "start": 0, "end": 30 } },
+            "DECODER": { "kind": "String", "value": "encodec", "possible_values": ["encodec", "multiband_diffusion"] },
         }
     },
     "MusicGenMedium": {
         "options": {
             "MODEL": { "hidden": True, "kind": "String", "value": "MusicGenMedium" },
             "POINT": { "hidden": True, "kind": "String", "value": "facebook/musicgen-medium" },
-            "CONDITION_PROMPT": { "kind": "String", "value": "140bpm dark techno hypnotic drums loop" },
+            "CONDITION_PROMPT": { "kind": "String", "required": True, "value": "140bpm dark techno hypnotic drums loop" },
             "SECONDS_TOTAL": { "kind": "Float", "value": 20, "range": { "start": 0, "end": 30 } },
+            "DECODER": { "kind": "String", "value": "encodec", "possible_values": ["encodec", "multiband_diffusion"] },
         }
     },
     "MusicGenLarge": {
         "options": {
             "MODEL": { "hidden": True, "kind": "String", "value": "MusicGenLarge" },
             "POINT": { "hidden": True, "kind": "String", "value": "facebook/musicgen-large" },
-            "CONDITION_PROMPT": { "kind": "String", "value": "140bpm dark techno hypnotic drums loop" },
+            "CONDITION_PROMPT": { "kind": "String", "required": True, "value": "140bpm dark techno hypnotic drums loop" },
             "SECONDS_TOTAL": { "kind": "Float", "value": 20, "range": { "start": 0, "end": 30 } },
+            "DECODER": { "kind": "String", "value": "encodec", "possible_values": ["encodec", "multiband_diffusion"] },
         }
     },
     "MusicGenMelody": {
         "options": {
             "MODEL": { "hidden": True, "kind": "String", "value": "MusicGenMelody" },
             "POINT": { "hidden": True, "kind": "String", "value": "facebook/musicgen-melody" },
-            "CONDITION_PROMPT": { "kind": "String", "value": "140bpm dark techno hypnotic drums loop" },
-            "CONDITION_SAMPLE": { "kind": "String", "value": "C:\\Users\\corbe\\Music\\2024-07-30_08-01-32.wav" },
+            "CONDITION_PROMPT": { "kind": "String", "required": True, "value": "140bpm dark techno hypnotic drums loop" },
+            "CONDITION_SAMPLE": { "kind": "AudioAsset", "value": None },
             "SECONDS_TOTAL": { "kind": "Float", "value": 20, "range": { "start": 0, "end": 30 } },
+            "DECODER": { "kind": "String", "value": "encodec", "possible_values": ["encodec", "multiband_diffusion"] },
         }
     },
     "MusicGenSongStarter": {
         "options": {
             "MODEL": { "hidden": True, "kind": "String", "value": "MusicGenSongStarter" },
             "POINT": { "hidden": True, "kind": "String", "value": "nateraw/musicgen-songstarter-v0.2" },
-            "CONDITION_PROMPT": { "kind": "String", "value": "140bpm dark techno hypnotic drums loop" },
-            "CONDITION_SAMPLE": { "kind": "String", "value": "C:\\Users\\corbe\\Music\\2024-07-30_08-01-32.wav" },
+            "CONDITION_PROMPT": { "kind": "String", "required": True, "value": "140bpm dark techno hypnotic drums loop" },
+            "CONDITION_SAMPLE": { "kind": "AudioAsset", "value": None },
             "SECONDS_TOTAL": { "kind": "Float", "value": 20, "range": { "start": 0, "end": 30 } },
+            "DECODER": { "kind": "String", "value": "encodec", "possible_values": ["encodec", "multiband_diffusion"] },
         }
+    },
+    "AudioGen": {
+        "options": {
+            "MODEL": { "hidden": True, "kind": "String", "value": "AudioGen" },
+            "POINT": { "hidden": True, "kind": "String", "value": "facebook/audiogen-medium" },
+            "CONDITION_PROMPT": { "kind": "String", "required": True, "value": "footsteps on wet gravel" },
+            "SECONDS_TOTAL": { "kind": "Float", "value": 5, "range": { "start": 0, "end": 30 } },
+            "TOP_K": { "kind": "Int", "value": 250, "range": { "start": 0, "end": 1000 } },
+            "TOP_P": { "kind": "Float", "value": 0, "range": { "start": 0, "end": 1 } },
+            "TEMPERATURE": { "kind": "Float", "value": 1, "range": { "start": 0, "end": 2 } },
+            "CFG_COEF": { "kind": "Float", "value": 3, "range": { "start": 0, "end": 10 } },
+        }
+    },
+    "StableAudioOpen": {
+        "options": {
+            "MODEL": { "hidden": True, "kind": "String", "value": "StableAudioOpen" },
+            "POINT": { "hidden": True, "kind": "String", "value": "stabilityai/stable-audio-open-1.0" },
+            "CONDITION_PROMPT": { "kind": "String", "required": True, "value": "140bpm dark techno hypnotic drums loop" },
+            "NEGATIVE_PROMPT": { "kind": "String", "value": "" },
+            "SECONDS_START": { "kind": "Float", "value": 0, "range": { "start": 0, "end": 47 } },
+            "SECONDS_TOTAL": { "kind": "Float", "value": 30, "range": { "start": 0, "end": 47 } },
+            "STEPS": { "kind": "Int", "value": 100, "range": { "start": 1, "end": 200 } },
+            "CFG_SCALE": { "kind": "Float", "value": 3, "range": { "start": 1, "end": 10 } },
+            "SIGMA_MIN": { "kind": "Float", "value": 0.3, "range": { "start": 0, "end": 1000 } },
+            "SIGMA_MAX": { "kind": "Float", "value": 500, "range": { "start": 0, "end": 1000 } },
+            "SAMPLER_TYPE": {
+                "kind": "Enum",
+                "value": "dpmpp-3m-sde",
+                "choices": [
+                    { "value": "dpmpp-2m-sde", "label": "DPM++ 2M SDE" },
+                    { "value": "dpmpp-3m-sde", "label": "DPM++ 3M SDE" },
+                    { "value": "k-heun", "label": "Heun" },
+                    { "value": "k-lms", "label": "LMS" },
+                    { "value": "k-dpmpp-2s-ancestral", "label": "DPM++ 2S Ancestral" },
+                    { "value": "k-dpm-2", "label": "DPM 2" },
+                    { "value": "k-dpm-fast", "label": "DPM Fast" },
+                    { "value": "k-dpm-adaptive", "label": "DPM Adaptive" },
+                ]
+            },
+        },
+        "rules": [
+            { "rule": "LessThan", "lesser": "SIGMA_MIN", "greater": "SIGMA_MAX" },
+            { "rule": "LessThan", "lesser": "SECONDS_START", "greater": "SECONDS_TOTAL" },
+        ]
     }
 }
 
+# UI metadata shared by every model exposing the option, the default is taken from the model definition
+option_meta = {
+    "CONDITION_PROMPT": { "label": "Prompt", "description": "Text description of the audio to generate", "group": "Conditioning", "order": 0, "widget": "text_area" },
+    "NEGATIVE_PROMPT": { "label": "Negative prompt", "description": "Text description of what the audio should avoid", "group": "Conditioning", "order": 1, "widget": "text_area" },
+    "CONDITION_SAMPLE": { "label": "Melody", "description": "Audio file whose melody conditions the generation", "group": "Conditioning", "order": 2, "widget": "file" },
+    "SECONDS_START": { "label": "Start", "description": "Timing conditioning offset", "unit": "s", "step": 0.5, "group": "Timing", "order": 10, "widget": "slider" },
+    "SECONDS_TOTAL": { "label": "Duration", "description": "Length of the generated audio", "unit": "s", "step": 0.5, "group": "Timing", "order": 11, "widget": "slider" },
+    "STEPS": { "label": "Steps", "description": "Number of diffusion steps", "step": 1, "group": "Sampling", "order": 20, "widget": "slider" },
+    "CFG_SCALE": { "label": "CFG scale", "description": "Strength of the prompt guidance", "step": 0.1, "group": "Sampling", "order": 21, "widget": "knob" },
+    "CFG_COEF": { "label": "CFG coefficient", "description": "Strength of the prompt guidance", "step": 0.1, "group": "Sampling", "order": 21, "widget": "knob" },
+    "SIGMA_MIN": { "label": "Sigma min", "description": "Lowest noise level of the sampler", "step": 0.01, "group": "Sampling", "order": 22, "widget": "knob" },
+    "SIGMA_MAX": { "label": "Sigma max", "description": "Highest noise level of the sampler", "step": 1, "group": "Sampling", "order": 23, "widget": "knob" },
+    "SAMPLER_TYPE": { "label": "Sampler", "description": "Diffusion sampler", "group": "Sampling", "order": 24 },
+    "TOP_K": { "label": "Top K", "description": "Sample among the K most likely tokens", "step": 1, "group": "Sampling", "order": 20, "widget": "slider" },
+    "TOP_P": { "label": "Top P", "description": "Nucleus sampling threshold, 0 disables it", "step": 0.01, "group": "Sampling", "order": 21, "widget": "knob" },
+    "TEMPERATURE": { "label": "Temperature", "description": "Randomness of the sampling", "step": 0.01, "group": "Sampling", "order": 22, "widget": "knob" },
+    "DECODER": { "label": "Decoder", "description": "Decoder turning tokens into audio, MultiBand Diffusion is slower but cleaner", "group": "Sampling", "order": 30 },
+    "SEED": { "label": "Seed", "description": "Random seed, fixed seeds make generations reproducible", "group": "Sampling", "order": 31 },
+    "DEVICE": { "label": "Device", "description": "Device running the model, auto prefers CUDA", "group": "Runtime", "order": 40 },
+}
+
 def generate_filename(prompt, idx):
     truncated_string = prompt[:12]
     pattern = r'[<>:"/\\|?*\x00-\x1F]'
     sanitized_string = re.sub(pattern, '_', truncated_string)
     sanitized_string = sanitized_string.strip()
     timestamp = time.strftime("%Y%m%d_%H%M%S")
-    filename = f"{truncated_string}_{idx}_{timestamp}"
+    # requests running in the same second must not overwrite each other
+    filename = f"{sanitized_string}_{idx}_{timestamp}_{uuid.uuid4().hex[:8]}"
     return filename
 
 def get_available_diffusion_model(request):
-    return [
-        "MusicGenSmall",
-        "MusicGenMedium",
-        "MusicGenLarge",
-        "MusicGenMelody",
-        "MusicGenSongStarter"
-    ]
+    return list(difussion_models.keys())
+
+def is_mbd_available():
+    try:
+        from audiocraft.models import MultiBandDiffusion
+        return True
+    except ImportError:
+        return False
+
+def get_mbd(device):
+    if device not in mbd:
+        from audiocraft.models import MultiBandDiffusion
+        mbd[device] = MultiBandDiffusion.get_mbd_musicgen(device=device)
+    return mbd[device]
+
+def available_devices():
+    devices = ["auto", "cpu"]
+    if torch.cuda.is_available():
+        devices += [f"cuda:{idx}" for idx in range(torch.cuda.device_count())]
+    return devices
+
+def resolve_device(options):
+    requested = options.get('DEVICE', {}).get('value') or "auto"
+    if requested == "auto":
+        return "cuda" if torch.cuda.is_available() else "cpu"
+    if requested.startswith("cuda") and (not torch.cuda.is_available() or (":" in requested and requested not in available_devices())):
+        log(f"device {requested} is not available, falling back to cpu", level="Warning")
+        return "cpu"
+    return requested
+
+def apply_seed(options):
+    seed = options.get('SEED', {}).get('value')
+    if seed is not None:
+        torch.manual_seed(int(seed))
+    return seed
+
+def get_runtime_info(request):
+    try:
+        import audiocraft
+        audiocraft_version = audiocraft.__version__
+    except (ImportError, AttributeError):
+        audiocraft_version = None
+
+    cpu = { "id": "cpu", "name": "cpu", "total_memory": None, "free_memory": None }
+    try:
+        import psutil
+        memory = psutil.virtual_memory()
+        cpu["total_memory"] = memory.total
+        cpu["free_memory"] = memory.available
+    except ImportError:
+        pass
+
+    devices = [cpu]
+    if torch.cuda.is_available():
+        for idx in range(torch.cuda.device_count()):
+            free_memory, total_memory = torch.cuda.mem_get_info(idx)
+            devices.append({
+                "id": f"cuda:{idx}",
+                "name": torch.cuda.get_device_name(idx),
+                "total_memory": total_memory,
+                "free_memory": free_memory,
+            })
+
+    return {
+        "torch_version": torch.__version__,
+        "audiocraft_version": audiocraft_version,
+        "cuda_available": torch.cuda.is_available(),
+        "devices": devices,
+    }
 
 def get_diffusion_model_template_task(request):
-    return difussion_models[request['model']]
+    template = copy.deepcopy(difussion_models[request['model']])
+    options = template['options']
+    options['DEVICE'] = { "kind": "String", "value": "auto", "possible_values": available_devices() }
+    options['SEED'] = { "kind": "Seed", "random": True }
+    if 'DECODER' in options:
+        mbd_available = is_mbd_available()
+        options['MBD_AVAILABLE'] = { "hidden": True, "kind": "Bool", "value": mbd_available }
+        if not mbd_available:
+            options['DECODER']['possible_values'] = ["encodec"]
+        template.setdefault('rules', []).append({
+            "rule": "VisibleIf",
+            "option": "DECODER",
+            "condition": { "test": "Equals", "option": "MBD_AVAILABLE", "value": True },
+        })
+    for key, option in options.items():
+        if key in option_meta:
+            option['meta'] = dict(option_meta[key], default=option.get('value'))
+    return template
 
-def run_diffusion_model_template_task(request):
-    options = request['template']['options']
+def run_musicgen(options):
     point = options['POINT']['value']
     output_directory = options['OUTPUT_DIRECTORY']['value']
     descriptions = [options['CONDITION_PROMPT']['value']]
 
-    model = MusicGen.get_pretrained(point)
-    model.set_generation_params(duration=8)
-    wav = model.generate(descriptions)
+    device = resolve_device(options)
+    model = MusicGen.get_pretrained(point, device=device)
+    model.set_generation_params(duration=float(options['SECONDS_TOTAL']['value']))
+    apply_seed(options)
+
+    # Long form generation: continue from the tail of the previous window.
+    # The output starts with the prompt re-generated, continuations are not conditioned on a melody.
+    continuation = options.get('CONTINUATION_SAMPLE', {}).get('value')
+    condition_sample = options.get('CONDITION_SAMPLE', {}).get('value')
+    if continuation:
+        prompt, prompt_sample_rate = torchaudio.load(continuation)
+        prompt_frames = int(float(options['CONTINUATION_SECONDS']['value']) * prompt_sample_rate)
+        prompt = prompt[..., -prompt_frames:]
+        generate = lambda **kwargs: model.generate_continuation(prompt, prompt_sample_rate, descriptions, **kwargs)
+    elif condition_sample:
+        melody, melody_sample_rate = torchaudio.load(condition_sample)
+        melody = melody[None].expand(len(descriptions), -1, -1)
+        generate = lambda **kwargs: model.generate_with_chroma(descriptions, melody, melody_sample_rate, **kwargs)
+    else:
+        generate = lambda **kwargs: model.generate(descriptions, **kwargs)
+
+    if options.get('DECODER', {}).get('value') == "multiband_diffusion":
+        _, tokens = generate(return_tokens=True)
+        wav = get_mbd(device).tokens_to_wav(tokens)
+    else:
+        wav = generate()
     
     output = []
     for idx, one_wav in enumerate(wav):
@@ -99,10 +275,108 @@ def run_diffusion_model_template_task(request):
         'assets': output
     }
 
+def run_audiogen(options):
+    point = options['POINT']['value']
+    output_directory = options['OUTPUT_DIRECTORY']['value']
+    descriptions = [options['CONDITION_PROMPT']['value']]
+
+    device = resolve_device(options)
+    model = AudioGen.get_pretrained(point, device=device)
+    model.set_generation_params(
+        duration=float(options['SECONDS_TOTAL']['value']),
+        top_k=int(options['TOP_K']['value']),
+        top_p=float(options['TOP_P']['value']),
+        temperature=float(options['TEMPERATURE']['value']),
+        cfg_coef=float(options['CFG_COEF']['value']),
+    )
+    apply_seed(options)
+    wav = model.generate(descriptions)
+
+    output = []
+    for idx, one_wav in enumerate(wav):
+        file_path = f'{output_directory}\\{generate_filename(descriptions[0], idx)}'
+        audio_write(file_path, one_wav.cpu(), model.sample_rate, strategy="loudness", loudness_compressor=True)
+        output.append(f'{file_path}.wav')
+
+    return {
+        'assets': output
+    }
+
+def run_stable_audio_open(options):
+    # stable_audio_tools is an optional dependency, only require it when the model is used
+    from einops import rearrange
+    from huggingface_hub import login
+    from stable_audio_tools import get_pretrained_model
+    from stable_audio_tools.inference.generation import generate_diffusion_cond
+
+    point = options['POINT']['value']
+    output_directory = options['OUTPUT_DIRECTORY']['value']
+    prompt = options['CONDITION_PROMPT']['value']
+
+    device = resolve_device(options)
+    seed = apply_seed(options)
+    login(token=options['HF_API_KEY']['value'])
+    model, model_config = get_pretrained_model(point)
+    sample_rate = model_config["sample_rate"]
+    sample_size = model_config["sample_size"]
+    model = model.to(device)
+
+    conditioning = [{
+        "prompt": prompt,
+        "seconds_start": float(options['SECONDS_START']['value']),
+        "seconds_total": float(options['SECONDS_TOTAL']['value']),
+    }]
+    negative_prompt = options.get('NEGATIVE_PROMPT', {}).get('value')
+    negative_conditioning = None
+    if negative_prompt:
+        negative_conditioning = [dict(conditioning[0], prompt=negative_prompt)]
+
+    output = generate_diffusion_cond(
+        model,
+        steps=int(options['STEPS']['value']),
+        cfg_scale=float(options['CFG_SCALE']['value']),
+        conditioning=conditioning,
+        negative_conditioning=negative_conditioning,
+        sample_size=sample_size,
+        sigma_min=float(options['SIGMA_MIN']['value']),
+        sigma_max=float(options['SIGMA_MAX']['value']),
+        sampler_type=options['SAMPLER_TYPE']['value'],
+        seed=-1 if seed is None else int(seed),
+        device=device
+    )
+
+    # (batch, channels, samples) -> (channels, samples), the model output is stereo
+    output = rearrange(output, "b d n -> d (b n)")
+    # the model always renders its whole window, keep the requested duration only
+    seconds_end = float(options['SECONDS_START']['value']) + float(options['SECONDS_TOTAL']['value'])
+    output = output[:, :int(seconds_end * sample_rate)]
+    output = output.to(torch.float32).div(torch.max(torch.abs(output))).clamp(-1, 1).mul(32767).to(torch.int16).cpu()
+    file_path = f'{output_directory}\\{generate_filename(prompt, 0)}.wav'
+    torchaudio.save(file_path, output, sample_rate)
+
+    return {
+        'assets': [file_path]
+    }
+
+model_runners = {
+    "MusicGenSmall": run_musicgen,
+    "MusicGenMedium": run_musicgen,
+    "MusicGenLarge": run_musicgen,
+    "MusicGenMelody": run_musicgen,
+    "MusicGenSongStarter": run_musicgen,
+    "AudioGen": run_audiogen,
+    "StableAudioOpen": run_stable_audio_open,
+}
+
+def run_diffusion_model_template_task(request):
+    options = request['template']['options']
+    return model_runners[options['MODEL']['value']](options)
+
 remote_procedure = {
     "GetDiffusionModelTemplateTask": get_diffusion_model_template_task,
     "RunDiffusionModelTemplateTask": run_diffusion_model_template_task,
     "GetAvailableDiffusionModel": get_available_diffusion_model,
+    "GetRuntimeInfo": get_runtime_info,
 }
 
 def handle(message):
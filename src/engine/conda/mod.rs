@@ -23,9 +23,12 @@ use tokio_util::codec::{BytesCodec, FramedRead};
 use tracing::instrument;
 
 use super::diffusion::{DiffusionModelOpt, StringOpt};
+use super::prompt::HostContext;
 use super::{
-    asset::Asset,
+    asset::{Asset, AssetMetadata},
     diffusion::DiffusionModelTemplate,
+    params::TemplateParams,
+    preset::PresetStore,
 };
 
 //@TODO
@@ -62,6 +65,7 @@ pub enum ToProcessRequest {
 #[derive(Clone)]
 pub struct CondaExecutor {
     entrypoint: PathBuf,
+    cache_directory: PathBuf,
     hf_api_key: String,
 
     remote_process: Arc<RwLock<RemoteProcess>>,
@@ -125,6 +129,34 @@ impl CondaExecutorTask for GetAvailableDiffusionModel {
     const ID: &'static str = "GetAvailableDiffusionModel";
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct GetRuntimeInfo {
+}
+
+/// Description of the python runtime backing the executor
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RuntimeInfo {
+    pub torch_version: String,
+    pub audiocraft_version: Option<String>,
+    pub cuda_available: bool,
+    pub devices: Vec<RuntimeDevice>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RuntimeDevice {
+    /// Value accepted by the `DEVICE` template option (`cpu`, `cuda:0`, ...)
+    pub id: String,
+    pub name: String,
+    /// Memory in bytes, not reported for the cpu when psutil is missing
+    pub total_memory: Option<u64>,
+    pub free_memory: Option<u64>,
+}
+
+impl CondaExecutorTask for GetRuntimeInfo {
+    type Result = RuntimeInfo;
+    const ID: &'static str = "GetRuntimeInfo";
+}
+
 impl CondaExecutor {
     pub async fn init(cache_directory: PathBuf) -> CaResult<Self> {
         let entrypoint = cache_directory.join("conda_executor.py");
@@ -142,6 +174,7 @@ impl CondaExecutor {
             ipc_proxy,
             hf_api_key: HF_API_KEY.to_string(),
             entrypoint,
+            cache_directory,
             last_call: Arc::new(AtomicUsize::new(0)),
         };
         Ok(instance)
@@ -186,11 +219,63 @@ impl CondaExecutor {
         self.call(GetAvailableDiffusionModel{}).await
     }
 
+    pub async fn get_diffusion_model_template(&self, model: &str) -> CaResult<DiffusionModelTemplate> {
+        self.call(GetDiffusionModelTemplateTask { model: model.to_string() }).await
+    }
+
+    /// Template of the parameters model with the typed values written into it
+    pub async fn get_diffusion_model_template_with<P: TemplateParams>(&self, params: P) -> CaResult<DiffusionModelTemplate> {
+        let template = self.get_diffusion_model_template(params.model()).await?;
+        Ok(params.apply_to(&template)?)
+    }
+
+    /// Preset store located in the executor cache directory
+    pub fn presets(&self) -> PresetStore {
+        PresetStore::new(&self.cache_directory)
+    }
+
+    /// Default template of `model` with the preset `name` applied over it
+    pub async fn get_diffusion_model_preset(&self, model: &str, name: &str) -> CaResult<DiffusionModelTemplate> {
+        let preset = self.presets().load(model, name).await?;
+        Ok(preset.apply(self.get_diffusion_model_template(model).await?))
+    }
+
+    pub async fn get_runtime_info(&self) -> CaResult<RuntimeInfo> {
+        self.call(GetRuntimeInfo{}).await
+    }
+
     pub async fn process_diffusion_model(
+        &self,
+        template: DiffusionModelTemplate,
+        output_directory: PathBuf,
+    ) -> CaResult<Vec<Asset>> {
+        self.process_diffusion_model_with_context(template, output_directory, &HostContext::default())
+            .await
+    }
+
+    /// Run the model after expanding the host variables (`{bpm}`, `{key}`, ...) of the prompt options
+    pub async fn process_diffusion_model_with_context(
         &self,
         mut template: DiffusionModelTemplate,
         output_directory: PathBuf,
+        context: &HostContext,
     ) -> CaResult<Vec<Asset>> {
+        let prompt_template = template
+            .options
+            .get("CONDITION_PROMPT")
+            .and_then(|opt| opt.as_string_value());
+        for (key, opt) in template.options.iter_mut() {
+            if let (true, Some(prompt)) = (key.ends_with("_PROMPT"), opt.as_string_value_mut()) {
+                *prompt = context.expand(prompt)?;
+            }
+        }
+        // validated once expanded, a prompt made only of empty variables is still missing
+        let violations = template.validate();
+        if !violations.is_empty() {
+            return Err(CaError::InvalidTemplate(violations));
+        }
+        template.resolve_inputs().await?;
+
         template.options.insert(
             "HF_API_KEY".to_string(),
             DiffusionModelOpt::String(StringOpt {
@@ -205,13 +290,26 @@ impl CondaExecutor {
                 ..Default::default()
             }),
         );
+        let mut metadata = AssetMetadata::from_template(&template);
+        metadata.prompt_template = prompt_template.filter(|prompt_template| Some(prompt_template) != metadata.prompt.as_ref());
+        metadata.started_at = Some(Local::now());
+        let started = std::time::Instant::now();
         let output = self.call(RunDiffusionModelTemplateTask { template })
             .await?;
+        metadata.finished_at = Some(Local::now());
+        metadata.generation_time = Some(started.elapsed().as_secs_f64());
         match output {
             RunDiffusionModelTemplateTaskResult { assets: Some(assets), error: None } => {
-                let assets = assets.into_iter().map(|path| {
+                let assets: Vec<Asset> = assets.into_iter().map(|path| {
                     Asset::Stored { path: path.into() }
                 }).collect();
+                for asset in assets.iter() {
+                    let mut metadata = metadata.clone();
+                    if let Err(e) = metadata.read_format(asset.path()).await {
+                        warn!(asset = ?asset.path(), "failed to read asset format: {}", e);
+                    }
+                    asset.write_metadata(&metadata).await?;
+                }
                 Ok(assets)
             },
             RunDiffusionModelTemplateTaskResult { error, ..} => Err(CaError::ExternalError { message: error }),
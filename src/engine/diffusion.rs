@@ -1,5 +1,7 @@
 use crate::prelude::*;
 
+use super::asset::Asset;
+
 macro_rules! impl_as_variant {
     ($variant:pat, $enum:path, $value_type:path) => {
         paste::paste!{
@@ -62,42 +64,285 @@ macro_rules! impl_as_variant {
 pub enum DiffusionModelOpt {
     Int(IntOpt),
     Float(FloatOpt),
-    String(StringOpt)
+    String(StringOpt),
+    Bool(BoolOpt),
+    Enum(EnumOpt),
+    AudioAsset(AudioAssetOpt),
+    Seed(SeedOpt),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// Ranges are inclusive on both ends (`SECONDS_TOTAL` 0..30 accepts 30)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub struct IntOpt {
     pub range: Option<Range<i64>>,
     pub value: Option<i64>,
     #[serde(default)]
     pub hidden: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OptionMeta>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+/// Ranges are inclusive on both ends (`SECONDS_TOTAL` 0..30 accepts 30)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub struct FloatOpt {
     pub range: Option<Range<f32>>,
     pub value: Option<f32>,
     #[serde(default)]
     pub hidden: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OptionMeta>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub struct StringOpt {
     pub possible_values: Option<Vec<String>>,
-    pub max_length: Option<String>,
+    /// Maximum length in characters
+    pub max_length: Option<usize>,
     pub value: Option<String>,
     #[serde(default)]
     pub hidden: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OptionMeta>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct BoolOpt {
+    pub value: Option<bool>,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OptionMeta>,
+}
+
+/// A choice between labelled values, `value` holds the `EnumChoice::value` sent to the worker
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct EnumOpt {
+    /// Empty in presets and overrides, which only carry the value
+    #[serde(default)]
+    pub choices: Vec<EnumChoice>,
+    pub value: Option<String>,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OptionMeta>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct EnumChoice {
+    pub value: String,
+    pub label: String,
+}
+
+/// Reference to an audio `Asset`, the path is resolved to an absolute one before being sent to the worker
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct AudioAssetOpt {
+    pub value: Option<PathBuf>,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OptionMeta>,
+}
+
+/// Generation seed, when `random` is set a new `value` is drawn on every dispatch
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct SeedOpt {
+    pub value: Option<u64>,
+    #[serde(default)]
+    pub random: bool,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<OptionMeta>,
+}
+
+/// Presentation hints used by front ends to render an option, every field is optional
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct OptionMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Display unit such as `s` or `dB`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// Increment between two consecutive values of a numeric option
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<f64>,
+    /// Value the option is reset to, same type as the option value. `Some(Null)` resets to no value.
+    #[serde(default, deserialize_with = "present_value", skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Position of the option in its group, lower first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub widget: Option<WidgetHint>,
+}
+
+/// A field present in the document, `null` included, missing fields fall back to `None` through `serde(default)`
+fn present_value<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<serde_json::Value>, D::Error> {
+    serde_json::Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum WidgetHint {
+    Slider,
+    Knob,
+    TextArea,
+    File,
+}
+
+/// Outcome of `DiffusionModelTemplate::apply_overrides`, keys are sorted
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct OverrideReport {
+    /// Overrides not matching any option of the template, they are not applied
+    pub unknown_keys: Vec<String>,
+    /// Overrides whose kind differs from the template option, they are not applied
+    pub kind_mismatches: Vec<KindMismatch>,
+    /// Hidden options that were overridden, they are applied
+    pub hidden_overridden: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KindMismatch {
+    pub key: String,
+    pub expected: String,
+    pub found: String,
+}
+
+/// A single option of a template that does not satisfy its own constraints
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, thiserror::Error)]
+#[serde(tag = "violation")]
+pub enum OptionViolation {
+    #[error("{key}: value {value} is out of range {start}..={end}")]
+    OutOfRange {
+        key: String,
+        value: f64,
+        start: f64,
+        end: f64,
+    },
+    #[error("{key}: value {value:?} is not one of {possible_values:?}")]
+    NotAllowed {
+        key: String,
+        value: String,
+        possible_values: Vec<String>,
+    },
+    #[error("{key}: value is {length} characters long, maximum is {max_length}")]
+    TooLong {
+        key: String,
+        length: usize,
+        max_length: usize,
+    },
+    #[error("{key}: a value is required")]
+    Missing {
+        key: String,
+    },
+    #[error("{key}: value must be lower than {other}")]
+    NotLessThan {
+        key: String,
+        other: String,
+    },
+    #[error("{key}: rule references an unknown option")]
+    UnknownOption {
+        key: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub struct DiffusionModelTemplate {
     pub options: HashMap<String, DiffusionModelOpt>,
+    /// Dependencies between options, evaluated by `is_visible`, `is_required` and `validate`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<OptionRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "rule")]
+pub enum OptionRule {
+    /// `option` is only relevant when `condition` holds
+    VisibleIf { option: String, condition: Condition },
+    /// `option` must have a value when `condition` holds
+    RequiredIf { option: String, condition: Condition },
+    /// When both are set, the value of `lesser` must be strictly lower than the value of `greater`
+    LessThan { lesser: String, greater: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "test")]
+pub enum Condition {
+    /// The option value equals `value`, numbers are compared as floats
+    Equals { option: String, value: serde_json::Value },
+    /// The option has a non empty value
+    IsSet { option: String },
+    Not { condition: Box<Condition> },
 }
 
 impl_as_variant!(Int, DiffusionModelOpt, i64);
 impl_as_variant!(Float, DiffusionModelOpt, f32);
 impl_as_variant!(String, DiffusionModelOpt, String);
+impl_as_variant!(Bool, DiffusionModelOpt, bool);
+impl_as_variant!(Enum, DiffusionModelOpt, String);
+impl_as_variant!(AudioAsset, DiffusionModelOpt, PathBuf);
+impl_as_variant!(Seed, DiffusionModelOpt, u64);
+
+impl From<&Asset> for AudioAssetOpt {
+    fn from(asset: &Asset) -> Self {
+        AudioAssetOpt {
+            value: Some(asset.path().to_owned()),
+            ..Default::default()
+        }
+    }
+}
+
+impl EnumOpt {
+    pub fn selected(&self) -> Option<&EnumChoice> {
+        let value = self.value.as_ref()?;
+        self.choices.iter().find(|choice| &choice.value == value)
+    }
+}
+
+impl SeedOpt {
+    pub fn random() -> Self {
+        SeedOpt {
+            random: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn fixed(seed: u64) -> Self {
+        SeedOpt {
+            value: Some(seed),
+            ..Default::default()
+        }
+    }
+
+    /// Draw a new value if the seed is random, the value is kept in the u32 range accepted by every backend
+    pub fn resolve(&mut self) -> u64 {
+        if self.random || self.value.is_none() {
+            use std::hash::{BuildHasher, Hasher};
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write_u128(Local::now().timestamp_nanos_opt().unwrap_or_default() as u128);
+            self.value = Some(hasher.finish() & u32::MAX as u64);
+        }
+        self.value.unwrap()
+    }
+}
 
 impl DiffusionModelOpt {
     pub fn into_raw_value(self) -> Option<String> {
@@ -105,6 +350,10 @@ impl DiffusionModelOpt {
             DiffusionModelOpt::Int(IntOpt { value: Some(value) , .. }) => Some(format!("{}", value)),
             DiffusionModelOpt::Float(FloatOpt { value: Some(value) , .. }) => Some(format!("{}", value)),
             DiffusionModelOpt::String(StringOpt { value: Some(value) , .. }) => Some(format!("{}", value)),
+            DiffusionModelOpt::Bool(BoolOpt { value: Some(value) , .. }) => Some(format!("{}", value)),
+            DiffusionModelOpt::Enum(EnumOpt { value: Some(value) , .. }) => Some(value),
+            DiffusionModelOpt::AudioAsset(AudioAssetOpt { value: Some(value) , .. }) => Some(format!("{}", value.display())),
+            DiffusionModelOpt::Seed(SeedOpt { value: Some(value) , .. }) => Some(format!("{}", value)),
             _ => None,
         }
     }
@@ -114,7 +363,567 @@ impl DiffusionModelOpt {
             DiffusionModelOpt::Int(IntOpt { hidden, .. }) => *hidden,
             DiffusionModelOpt::Float(FloatOpt { hidden, .. }) => *hidden,
             DiffusionModelOpt::String(StringOpt { hidden, .. }) => *hidden,
+            DiffusionModelOpt::Bool(BoolOpt { hidden, .. }) => *hidden,
+            DiffusionModelOpt::Enum(EnumOpt { hidden, .. }) => *hidden,
+            DiffusionModelOpt::AudioAsset(AudioAssetOpt { hidden, .. }) => *hidden,
+            DiffusionModelOpt::Seed(SeedOpt { hidden, .. }) => *hidden,
+        }
+    }
+
+    pub fn meta(&self) -> Option<&OptionMeta> {
+        match self {
+            DiffusionModelOpt::Int(IntOpt { meta, .. }) => meta.as_ref(),
+            DiffusionModelOpt::Float(FloatOpt { meta, .. }) => meta.as_ref(),
+            DiffusionModelOpt::String(StringOpt { meta, .. }) => meta.as_ref(),
+            DiffusionModelOpt::Bool(BoolOpt { meta, .. }) => meta.as_ref(),
+            DiffusionModelOpt::Enum(EnumOpt { meta, .. }) => meta.as_ref(),
+            DiffusionModelOpt::AudioAsset(AudioAssetOpt { meta, .. }) => meta.as_ref(),
+            DiffusionModelOpt::Seed(SeedOpt { meta, .. }) => meta.as_ref(),
+        }
+    }
+
+    pub fn required(&self) -> bool {
+        match self {
+            DiffusionModelOpt::Int(IntOpt { required, .. }) => *required,
+            DiffusionModelOpt::Float(FloatOpt { required, .. }) => *required,
+            DiffusionModelOpt::String(StringOpt { required, .. }) => *required,
+            DiffusionModelOpt::Bool(BoolOpt { required, .. }) => *required,
+            DiffusionModelOpt::Enum(EnumOpt { required, .. }) => *required,
+            DiffusionModelOpt::AudioAsset(AudioAssetOpt { required, .. }) => *required,
+            // a random seed is always resolved before dispatch
+            DiffusionModelOpt::Seed(SeedOpt { required, random, .. }) => *required && !*random,
+        }
+    }
+
+    /// Copy the value of `other` into `self`, keeping `self` constraints.
+    /// Returns false and leaves `self` untouched when both options are of different kinds.
+    pub fn assign_value(&mut self, other: &DiffusionModelOpt) -> bool {
+        match (self, other) {
+            (DiffusionModelOpt::Int(left), DiffusionModelOpt::Int(right)) => left.value = right.value,
+            (DiffusionModelOpt::Float(left), DiffusionModelOpt::Float(right)) => left.value = right.value,
+            (DiffusionModelOpt::String(left), DiffusionModelOpt::String(right)) => left.value = right.value.clone(),
+            (DiffusionModelOpt::Bool(left), DiffusionModelOpt::Bool(right)) => left.value = right.value,
+            (DiffusionModelOpt::Enum(left), DiffusionModelOpt::Enum(right)) => left.value = right.value.clone(),
+            (DiffusionModelOpt::AudioAsset(left), DiffusionModelOpt::AudioAsset(right)) => left.value = right.value.clone(),
+            (DiffusionModelOpt::Seed(left), DiffusionModelOpt::Seed(right)) => {
+                left.value = right.value;
+                left.random = right.random;
+            }
+            _ => return false,
         }
+        true
     }
 
-}
\ No newline at end of file
+    /// Value of the option as JSON, `Null` when unset
+    pub fn json_value(&self) -> serde_json::Value {
+        match self {
+            DiffusionModelOpt::Int(IntOpt { value, .. }) => serde_json::json!(value),
+            DiffusionModelOpt::Float(FloatOpt { value, .. }) => serde_json::json!(value),
+            DiffusionModelOpt::String(StringOpt { value, .. }) => serde_json::json!(value),
+            DiffusionModelOpt::Bool(BoolOpt { value, .. }) => serde_json::json!(value),
+            DiffusionModelOpt::Enum(EnumOpt { value, .. }) => serde_json::json!(value),
+            DiffusionModelOpt::AudioAsset(AudioAssetOpt { value, .. }) => serde_json::json!(value),
+            DiffusionModelOpt::Seed(SeedOpt { value, .. }) => serde_json::json!(value),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DiffusionModelOpt::Int(_) => "Int",
+            DiffusionModelOpt::Float(_) => "Float",
+            DiffusionModelOpt::String(_) => "String",
+            DiffusionModelOpt::Bool(_) => "Bool",
+            DiffusionModelOpt::Enum(_) => "Enum",
+            DiffusionModelOpt::AudioAsset(_) => "AudioAsset",
+            DiffusionModelOpt::Seed(_) => "Seed",
+        }
+    }
+
+    /// JSON Schema of a single option, non standard keywords are prefixed with `x-`
+    pub fn to_json_schema(&self) -> serde_json::Value {
+        let mut schema = match self {
+            DiffusionModelOpt::Int(IntOpt { range, value, .. }) => serde_json::json!({
+                "type": "integer",
+                "minimum": range.as_ref().map(|range| range.start),
+                "maximum": range.as_ref().map(|range| range.end),
+                "default": value,
+            }),
+            DiffusionModelOpt::Float(FloatOpt { range, value, .. }) => serde_json::json!({
+                "type": "number",
+                "minimum": range.as_ref().map(|range| range.start),
+                "maximum": range.as_ref().map(|range| range.end),
+                "default": value,
+            }),
+            DiffusionModelOpt::String(StringOpt { possible_values, max_length, value, .. }) => serde_json::json!({
+                "type": "string",
+                "enum": possible_values,
+                "maxLength": max_length,
+                "default": value,
+            }),
+            DiffusionModelOpt::Bool(BoolOpt { value, .. }) => serde_json::json!({
+                "type": "boolean",
+                "default": value,
+            }),
+            DiffusionModelOpt::Enum(EnumOpt { choices, value, .. }) => serde_json::json!({
+                "type": "string",
+                "oneOf": choices.iter().map(|choice| serde_json::json!({
+                    "const": choice.value,
+                    "title": choice.label,
+                })).collect::<Vec<_>>(),
+                "default": value,
+            }),
+            DiffusionModelOpt::AudioAsset(AudioAssetOpt { value, .. }) => serde_json::json!({
+                "type": "string",
+                "contentMediaType": "audio/wav",
+                "default": value,
+            }),
+            DiffusionModelOpt::Seed(SeedOpt { value, random, .. }) => serde_json::json!({
+                "type": "integer",
+                "minimum": 0,
+                "maximum": u32::MAX,
+                "default": value,
+                "x-random": random,
+            }),
+        };
+        let object = schema.as_object_mut().unwrap();
+        if let Some(meta) = self.meta() {
+            object.extend([
+                ("title", meta.label.clone().into()),
+                ("description", meta.description.clone().into()),
+                ("x-unit", meta.unit.clone().into()),
+                ("x-step", meta.step.into()),
+                ("x-group", meta.group.clone().into()),
+                ("x-order", meta.order.into()),
+                ("x-widget", serde_json::to_value(meta.widget).unwrap()),
+            ].map(|(key, value)| (key.to_string(), value)));
+            if let Some(default) = meta.default.as_ref() {
+                object.insert("default".to_string(), default.clone());
+            }
+        }
+        object.retain(|_, value| !value.is_null());
+        object.insert("x-kind".to_string(), self.kind().into());
+        if self.hidden() {
+            object.insert("x-hidden".to_string(), true.into());
+        }
+        schema
+    }
+
+    /// Check the option value against its own constraints, `key` is only used to label violations
+    pub fn validate(&self, key: &str) -> Vec<OptionViolation> {
+        let mut violations = vec![];
+        let out_of_range = |value: f64, start: f64, end: f64| OptionViolation::OutOfRange {
+            key: key.to_string(),
+            value,
+            start,
+            end,
+        };
+        match self {
+            DiffusionModelOpt::Int(IntOpt { range: Some(range), value: Some(value), .. })
+                if *value < range.start || *value > range.end =>
+            {
+                violations.push(out_of_range(*value as f64, range.start as f64, range.end as f64));
+            }
+            DiffusionModelOpt::Float(FloatOpt { range: Some(range), value: Some(value), .. })
+                if value.is_nan() || *value < range.start || *value > range.end =>
+            {
+                violations.push(out_of_range(*value as f64, range.start as f64, range.end as f64));
+            }
+            DiffusionModelOpt::String(StringOpt { possible_values, max_length, value: Some(value), .. }) => {
+                if let Some(possible_values) = possible_values {
+                    if !possible_values.contains(value) {
+                        violations.push(OptionViolation::NotAllowed {
+                            key: key.to_string(),
+                            value: value.clone(),
+                            possible_values: possible_values.clone(),
+                        });
+                    }
+                }
+                if let Some(max_length) = max_length {
+                    let length = value.chars().count();
+                    if length > *max_length {
+                        violations.push(OptionViolation::TooLong {
+                            key: key.to_string(),
+                            length,
+                            max_length: *max_length,
+                        });
+                    }
+                }
+            }
+            DiffusionModelOpt::Enum(EnumOpt { choices, value: Some(value), .. })
+                if !choices.iter().any(|choice| &choice.value == value) =>
+            {
+                violations.push(OptionViolation::NotAllowed {
+                    key: key.to_string(),
+                    value: value.clone(),
+                    possible_values: choices.iter().map(|choice| choice.value.clone()).collect(),
+                });
+            }
+            _ => {}
+        }
+        if self.required() && self.clone().into_raw_value().is_none_or(|value| value.is_empty()) {
+            violations.push(OptionViolation::Missing { key: key.to_string() });
+        }
+        violations
+    }
+}
+
+impl OverrideReport {
+    pub fn is_clean(&self) -> bool {
+        self.unknown_keys.is_empty() && self.kind_mismatches.is_empty() && self.hidden_overridden.is_empty()
+    }
+}
+
+/// Options never written outside of the worker call
+pub const SECRET_OPTIONS: &[&str] = &["HF_API_KEY"];
+
+impl DiffusionModelTemplate {
+    /// Copy of the template with the value of secret options replaced by `[REDACTED]`
+    pub fn redacted(&self) -> DiffusionModelTemplate {
+        let mut template = self.clone();
+        for (key, opt) in template.options.iter_mut() {
+            let secret = SECRET_OPTIONS.contains(&key.as_str()) || key.ends_with("_TOKEN");
+            if let (true, Some(value)) = (secret, opt.as_string_value_mut()) {
+                *value = "[REDACTED]".to_string();
+            }
+        }
+        template
+    }
+
+    /// Collect every option violating its range, possible values, maximum length or required flag.
+    /// Violations are sorted by option key.
+    pub fn validate(&self) -> Vec<OptionViolation> {
+        let mut keys: Vec<&String> = self.options.keys().collect();
+        keys.sort();
+        let mut violations: Vec<OptionViolation> = keys
+            .into_iter()
+            .flat_map(|key| self.options[key].validate(key))
+            .collect();
+        violations.extend(self.validate_rules());
+        violations
+    }
+
+    /// Evaluate `condition` against the current option values, conditions on unknown options are false
+    pub fn evaluate(&self, condition: &Condition) -> bool {
+        match condition {
+            Condition::Equals { option, value } => self.options.get(option).is_some_and(|opt| {
+                match (opt.json_value(), value) {
+                    (serde_json::Value::Number(left), serde_json::Value::Number(right)) => {
+                        left.as_f64() == right.as_f64()
+                    }
+                    (left, right) => &left == right,
+                }
+            }),
+            Condition::IsSet { option } => self
+                .options
+                .get(option)
+                .and_then(|opt| opt.clone().into_raw_value())
+                .is_some_and(|value| !value.is_empty()),
+            Condition::Not { condition } => !self.evaluate(condition),
+        }
+    }
+
+    /// An option is visible when it is not hidden and every `VisibleIf` rule targeting it holds
+    pub fn is_visible(&self, key: &str) -> bool {
+        self.options.get(key).is_some_and(|opt| !opt.hidden())
+            && self.rules.iter().all(|rule| match rule {
+                OptionRule::VisibleIf { option, condition } if option == key => self.evaluate(condition),
+                _ => true,
+            })
+    }
+
+    /// Visible option keys, sorted
+    pub fn visible_options(&self) -> Vec<&String> {
+        let mut keys: Vec<&String> = self.options.keys().filter(|key| self.is_visible(key)).collect();
+        keys.sort();
+        keys
+    }
+
+    /// An option is required when flagged as such or when a `RequiredIf` rule targeting it holds
+    pub fn is_required(&self, key: &str) -> bool {
+        self.options.get(key).is_some_and(|opt| opt.required())
+            || self.rules.iter().any(|rule| match rule {
+                OptionRule::RequiredIf { option, condition } if option == key => self.evaluate(condition),
+                _ => false,
+            })
+    }
+
+    fn validate_rules(&self) -> Vec<OptionViolation> {
+        let mut violations = vec![];
+        let check_known = |key: &String, violations: &mut Vec<OptionViolation>| {
+            if !self.options.contains_key(key) {
+                violations.push(OptionViolation::UnknownOption { key: key.clone() });
+            }
+        };
+        for rule in self.rules.iter() {
+            match rule {
+                OptionRule::VisibleIf { option, .. } => check_known(option, &mut violations),
+                OptionRule::RequiredIf { option, condition } => {
+                    check_known(option, &mut violations);
+                    let is_set = self.evaluate(&Condition::IsSet { option: option.clone() });
+                    if self.evaluate(condition) && self.is_visible(option) && !is_set {
+                        violations.push(OptionViolation::Missing { key: option.clone() });
+                    }
+                }
+                OptionRule::LessThan { lesser, greater } => {
+                    check_known(lesser, &mut violations);
+                    check_known(greater, &mut violations);
+                    let number = |key: &String| self.options.get(key).and_then(|opt| opt.json_value().as_f64());
+                    if let (Some(left), Some(right)) = (number(lesser), number(greater)) {
+                        if left >= right {
+                            violations.push(OptionViolation::NotLessThan {
+                                key: lesser.clone(),
+                                other: greater.clone(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        violations
+    }
+
+    /// Merge the values of `overrides` over a copy of the template.
+    /// Constraints and metadata of the template are kept, only values are taken from the overrides.
+    pub fn apply_overrides(&self, overrides: &DiffusionModelTemplate) -> (DiffusionModelTemplate, OverrideReport) {
+        let mut merged = self.clone();
+        let mut report = OverrideReport::default();
+        let mut keys: Vec<&String> = overrides.options.keys().collect();
+        keys.sort();
+        for key in keys {
+            let opt = &overrides.options[key];
+            match merged.options.get_mut(key) {
+                None => report.unknown_keys.push(key.clone()),
+                Some(target) => {
+                    if !target.assign_value(opt) {
+                        report.kind_mismatches.push(KindMismatch {
+                            key: key.clone(),
+                            expected: target.kind().to_string(),
+                            found: opt.kind().to_string(),
+                        });
+                    } else if target.hidden() {
+                        report.hidden_overridden.push(key.clone());
+                    }
+                }
+            }
+        }
+        (merged, report)
+    }
+
+    /// Export the template as a JSON Schema (draft 2020-12) object document,
+    /// every option is a property and options required by their flag or by a `RequiredIf` rule holding
+    /// for the current values are listed in `required`.
+    pub fn to_json_schema(&self) -> serde_json::Value {
+        let properties: serde_json::Map<String, serde_json::Value> = self
+            .options
+            .iter()
+            .map(|(key, opt)| (key.clone(), opt.to_json_schema()))
+            .collect();
+        let mut required: Vec<&String> = self.options.keys().filter(|key| self.is_required(key)).collect();
+        required.sort();
+        let mut schema = serde_json::json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        });
+        if !self.rules.is_empty() {
+            schema["x-rules"] = serde_json::to_value(&self.rules).unwrap();
+        }
+        schema
+    }
+
+    /// Resolve the options that can't be sent as is to the worker:
+    /// random seeds are drawn and audio asset paths are made absolute.
+    pub async fn resolve_inputs(&mut self) -> CaResult<()> {
+        for opt in self.options.values_mut() {
+            match opt {
+                DiffusionModelOpt::Seed(seed) => {
+                    seed.resolve();
+                }
+                DiffusionModelOpt::AudioAsset(AudioAssetOpt { value: Some(path), .. }) => {
+                    *path = tokio::fs::canonicalize(&path)
+                        .await
+                        .map_err(|_| CaError::MissingAsset(path.clone()))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn melody_template(mode: &str) -> DiffusionModelTemplate {
+        serde_json::from_value(serde_json::json!({
+            "options": {
+                "MODE": {
+                    "kind": "Enum",
+                    "value": mode,
+                    "choices": [
+                        { "value": "text", "label": "Text" },
+                        { "value": "melody", "label": "Melody" }
+                    ]
+                },
+                "CONDITION_PROMPT": { "kind": "String", "required": true, "value": "" },
+                "CONDITION_SAMPLE": { "kind": "AudioAsset", "value": null },
+                "STEPS": { "kind": "Int", "value": 500, "range": { "start": 1, "end": 200 } },
+                "SIGMA_MIN": { "kind": "Float", "value": 5, "range": { "start": 0, "end": 1000 } },
+                "SIGMA_MAX": { "kind": "Float", "value": 1, "range": { "start": 0, "end": 1000 } }
+            },
+            "rules": [
+                {
+                    "rule": "VisibleIf",
+                    "option": "CONDITION_SAMPLE",
+                    "condition": { "test": "Equals", "option": "MODE", "value": "melody" }
+                },
+                {
+                    "rule": "RequiredIf",
+                    "option": "CONDITION_SAMPLE",
+                    "condition": { "test": "Equals", "option": "MODE", "value": "melody" }
+                },
+                { "rule": "LessThan", "lesser": "SIGMA_MIN", "greater": "SIGMA_MAX" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn validate_reports_options_then_rules() {
+        let violations = melody_template("melody").validate();
+        assert_eq!(
+            violations,
+            vec![
+                OptionViolation::Missing {
+                    key: "CONDITION_PROMPT".to_string()
+                },
+                OptionViolation::OutOfRange {
+                    key: "STEPS".to_string(),
+                    value: 500.0,
+                    start: 1.0,
+                    end: 200.0
+                },
+                OptionViolation::Missing {
+                    key: "CONDITION_SAMPLE".to_string()
+                },
+                OptionViolation::NotLessThan {
+                    key: "SIGMA_MIN".to_string(),
+                    other: "SIGMA_MAX".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unknown_enum_values() {
+        let violations = melody_template("drums").validate();
+        assert!(violations.contains(&OptionViolation::NotAllowed {
+            key: "MODE".to_string(),
+            value: "drums".to_string(),
+            possible_values: vec!["text".to_string(), "melody".to_string()],
+        }));
+    }
+
+    #[test]
+    fn rules_follow_the_current_values() {
+        let melody = melody_template("melody");
+        assert!(melody.is_visible("CONDITION_SAMPLE"));
+        assert!(melody.is_required("CONDITION_SAMPLE"));
+        let text = melody_template("text");
+        assert!(!text.is_visible("CONDITION_SAMPLE"));
+        assert!(!text.is_required("CONDITION_SAMPLE"));
+        assert!(!text
+            .validate()
+            .contains(&OptionViolation::Missing { key: "CONDITION_SAMPLE".to_string() }));
+    }
+
+    #[test]
+    fn json_schema_lists_options_required_by_rules() {
+        let schema = melody_template("melody").to_json_schema();
+        assert_eq!(schema["required"], serde_json::json!(["CONDITION_PROMPT", "CONDITION_SAMPLE"]));
+        let schema = melody_template("text").to_json_schema();
+        assert_eq!(schema["required"], serde_json::json!(["CONDITION_PROMPT"]));
+        assert_eq!(schema["properties"]["STEPS"]["maximum"], 200);
+    }
+
+    #[test]
+    fn overrides_only_carry_values() {
+        let base: DiffusionModelTemplate = serde_json::from_value(serde_json::json!({
+            "options": {
+                "MODEL": { "hidden": true, "kind": "String", "value": "MusicGenSmall" },
+                "SECONDS_TOTAL": {
+                    "kind": "Float",
+                    "value": 10,
+                    "range": { "start": 1, "end": 30 },
+                    "meta": { "label": "Duration", "unit": "s" }
+                },
+                "STEPS": { "kind": "Int", "value": 50, "range": { "start": 1, "end": 200 } }
+            },
+            "rules": [{ "rule": "LessThan", "lesser": "STEPS", "greater": "SECONDS_TOTAL" }]
+        }))
+        .unwrap();
+        let overrides: DiffusionModelTemplate = serde_json::from_value(serde_json::json!({
+            "options": {
+                "MODEL": { "kind": "String", "value": "MusicGenLarge" },
+                "SECONDS_TOTAL": { "kind": "Float", "value": 20 },
+                "SECOND_TOTAL": { "kind": "Float", "value": 5 },
+                "STEPS": { "kind": "String", "value": "100" }
+            }
+        }))
+        .unwrap();
+
+        let (merged, report) = base.apply_overrides(&overrides);
+        assert_eq!(
+            report,
+            OverrideReport {
+                unknown_keys: vec!["SECOND_TOTAL".to_string()],
+                kind_mismatches: vec![KindMismatch {
+                    key: "STEPS".to_string(),
+                    expected: "Int".to_string(),
+                    found: "String".to_string(),
+                }],
+                hidden_overridden: vec!["MODEL".to_string()],
+            }
+        );
+        assert!(!report.is_clean());
+        assert!(!merged.options.contains_key("SECOND_TOTAL"));
+        assert_eq!(merged.options["STEPS"], base.options["STEPS"]);
+        assert_eq!(merged.options["MODEL"].as_string_value(), Some("MusicGenLarge".to_string()));
+        assert!(merged.options["MODEL"].hidden());
+        let seconds = merged.options["SECONDS_TOTAL"].as_float_ref().unwrap();
+        assert_eq!(seconds.value, Some(20.0));
+        assert_eq!(seconds.range, Some(1.0..30.0));
+        assert_eq!(merged.options["SECONDS_TOTAL"].meta(), base.options["SECONDS_TOTAL"].meta());
+        assert_eq!(merged.rules, base.rules);
+    }
+
+    #[test]
+    fn worker_option_meta_roundtrips() {
+        let worker = serde_json::json!({
+            "kind": "Seed",
+            "random": true,
+            "meta": {
+                "label": "Seed",
+                "description": "Same seed and options give the same audio",
+                "group": "Generation",
+                "order": 10,
+                "widget": "text_area",
+                "default": null
+            }
+        });
+        let opt: DiffusionModelOpt = serde_json::from_value(worker.clone()).unwrap();
+        let meta = opt.meta().unwrap();
+        assert_eq!(meta.widget, Some(WidgetHint::TextArea));
+        assert_eq!(meta.default, Some(serde_json::Value::Null));
+        let serialized = serde_json::to_value(&opt).unwrap();
+        assert_eq!(serialized["meta"], worker["meta"]);
+        assert_eq!(serde_json::from_value::<DiffusionModelOpt>(serialized).unwrap(), opt);
+
+        let without_default: OptionMeta = serde_json::from_value(serde_json::json!({ "step": 0.5 })).unwrap();
+        assert_eq!(without_default.default, None);
+        assert_eq!(serde_json::to_value(&without_default).unwrap(), serde_json::json!({ "step": 0.5 }));
+    }
+}
@@ -0,0 +1,371 @@
+use std::time::{Duration, SystemTime};
+
+use crate::prelude::*;
+use tokio_util::sync::CancellationToken;
+
+use super::asset::Asset;
+use super::library::{AssetLibrary, AssetQuery};
+use super::store::ContentHash;
+
+/// Extensions of the files the collector may delete, along with their companion files
+pub const AUDIO_EXTENSIONS: &[&str] = &["wav", "aif", "aiff", "aifc", "flac", "mp3", "ogg", "pcm", "raw"];
+
+/// Rules deciding which generated files are deleted, unset limits keep everything
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub struct RetentionPolicy {
+    /// Files last modified before this age are deleted
+    pub max_age: Option<Duration>,
+    /// Oldest files are deleted until the directories fit in this many bytes
+    pub max_total_size: Option<u64>,
+    /// Never delete the library favorites, they still count in the total size
+    pub keep_favorites: bool,
+}
+
+/// Applies a `RetentionPolicy` to the cache and output directories (recursively).
+/// Only audio assets (see `AUDIO_EXTENSIONS`) with a metadata sidecar are collected, `AssetStore`
+/// objects and excluded directories are never touched. Sidecars and other companion files are
+/// deleted with their asset and counted in its size.
+#[derive(Debug, Clone)]
+pub struct GarbageCollector {
+    directories: Vec<PathBuf>,
+    excluded: Vec<PathBuf>,
+    policy: RetentionPolicy,
+    library: Option<AssetLibrary>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcReport {
+    /// Deleted assets, companion files not included
+    pub removed: Vec<PathBuf>,
+    /// Bytes freed, companion files included
+    pub reclaimed_bytes: u64,
+    /// Library entries dropped because their file was deleted
+    pub forgotten: Vec<u64>,
+    /// Files the policy would have deleted but were kept as favorites
+    pub kept_favorites: usize,
+}
+
+/// Background collection started by `GarbageCollector::spawn`
+#[derive(Debug)]
+pub struct GcHandle {
+    cancellation: CancellationToken,
+    task: tokio::task::JoinHandle<()>,
+}
+
+struct Candidate {
+    asset: Asset,
+    size: u64,
+    modified: SystemTime,
+    /// Library entry of the asset
+    entry: Option<LibraryRef>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LibraryRef {
+    id: u64,
+    favorite: bool,
+}
+
+impl RetentionPolicy {
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn max_total_size(mut self, bytes: u64) -> Self {
+        self.max_total_size = Some(bytes);
+        self
+    }
+
+    pub fn keep_favorites(mut self, keep: bool) -> Self {
+        self.keep_favorites = keep;
+        self
+    }
+}
+
+impl GcHandle {
+    /// Stop the periodic collection, a collection in progress is finished first
+    pub async fn stop(self) {
+        self.cancellation.cancel();
+        if let Err(e) = self.task.await {
+            warn!("garbage collector task failed: {}", e);
+        }
+    }
+}
+
+impl GarbageCollector {
+    pub fn new(directories: Vec<PathBuf>, policy: RetentionPolicy) -> Self {
+        Self {
+            directories,
+            excluded: vec![],
+            policy,
+            library: None,
+        }
+    }
+
+    /// Never look into `directory`, e.g. an `AssetStore` root nested in a collected directory
+    pub fn exclude(mut self, directory: PathBuf) -> Self {
+        self.excluded.push(directory);
+        self
+    }
+
+    /// Library providing the favorites, entries of deleted files are dropped from it
+    pub fn with_library(mut self, library: AssetLibrary) -> Self {
+        self.library = Some(library);
+        self
+    }
+
+    pub async fn collect(&self) -> CaResult<GcReport> {
+        let mut report = GcReport::default();
+        // entries are matched by canonical path before anything is deleted, canonicalization
+        // needs the file to exist and the library stores paths as they were added
+        let indexed = self.library_entries().await;
+        let mut candidates = self.candidates(&indexed).await?;
+        // oldest first, so the size limit deletes the oldest files
+        candidates.sort_by_key(|candidate| candidate.modified);
+
+        let now = SystemTime::now();
+        let mut total_size: u64 = candidates.iter().map(|candidate| candidate.size).sum();
+        let mut forgotten = HashSet::new();
+        for candidate in candidates.iter() {
+            let expired = self.policy.max_age.is_some_and(|max_age| {
+                now.duration_since(candidate.modified).is_ok_and(|age| age > max_age)
+            });
+            let over_quota = self.policy.max_total_size.is_some_and(|max| total_size > max);
+            if !expired && !over_quota {
+                continue;
+            }
+            if candidate.entry.is_some_and(|entry| entry.favorite) && self.policy.keep_favorites {
+                report.kept_favorites += 1;
+                continue;
+            }
+            match remove_asset(&candidate.asset).await {
+                Ok(()) => {
+                    total_size -= candidate.size;
+                    report.reclaimed_bytes += candidate.size;
+                    report.removed.push(candidate.asset.path().to_owned());
+                    forgotten.extend(candidate.entry.map(|entry| entry.id));
+                }
+                Err(e) => warn!(path = ?candidate.asset.path(), "garbage collector could not delete: {}", e),
+            }
+        }
+
+        if let Some(library) = self.library.as_ref() {
+            report.forgotten = library.forget(&forgotten).await?;
+        }
+        Ok(report)
+    }
+
+    /// Run `collect` every `interval` until the returned handle is stopped
+    pub fn spawn(self, interval: Duration) -> GcHandle {
+        let cancellation = CancellationToken::new();
+        let token = cancellation.clone();
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                match self.collect().await {
+                    Ok(report) if !report.removed.is_empty() => info!(
+                        files = report.removed.len(),
+                        bytes = report.reclaimed_bytes,
+                        "garbage collector reclaimed disk space"
+                    ),
+                    Ok(_) => {}
+                    Err(e) => error!("garbage collection failed: {}", e),
+                }
+            }
+        });
+        GcHandle { cancellation, task }
+    }
+
+    /// Library entries by canonical path
+    async fn library_entries(&self) -> HashMap<PathBuf, LibraryRef> {
+        let Some(library) = self.library.as_ref() else {
+            return HashMap::new();
+        };
+        let mut entries = HashMap::new();
+        for entry in library.query(&AssetQuery::default()).await {
+            let path = tokio::fs::canonicalize(&entry.path).await.unwrap_or(entry.path);
+            entries.insert(
+                path,
+                LibraryRef {
+                    id: entry.id,
+                    favorite: entry.favorite,
+                },
+            );
+        }
+        entries
+    }
+
+    async fn candidates(&self, indexed: &HashMap<PathBuf, LibraryRef>) -> CaResult<Vec<Candidate>> {
+        let mut candidates = vec![];
+        let mut excluded = HashSet::new();
+        for directory in self.excluded.iter() {
+            excluded.insert(tokio::fs::canonicalize(directory).await.unwrap_or(directory.clone()));
+        }
+        let mut directories = vec![];
+        for directory in self.directories.iter() {
+            match tokio::fs::canonicalize(directory).await {
+                Ok(directory) => directories.push(directory),
+                Err(e) => warn!(directory = ?directory, "garbage collector skipped a directory: {}", e),
+            }
+        }
+        let mut visited = HashSet::new();
+        while let Some(directory) = directories.pop() {
+            if excluded.contains(&directory) || !visited.insert(directory.clone()) {
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(&directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                let path = entry.path();
+                if file_type.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                if !file_type.is_file() || !is_audio(&path) || is_store_object(&path) {
+                    continue;
+                }
+                let asset = Asset::Stored { path };
+                // only generated assets carry a sidecar, other audio files belong to the user
+                if !tokio::fs::try_exists(asset.sidecar_path()).await? {
+                    continue;
+                }
+                let metadata = entry.metadata().await?;
+                let mut size = metadata.len();
+                for companion in asset.companion_paths() {
+                    if let Ok(metadata) = tokio::fs::metadata(companion).await {
+                        size += metadata.len();
+                    }
+                }
+                candidates.push(Candidate {
+                    entry: indexed.get(asset.path()).copied(),
+                    asset,
+                    size,
+                    modified: metadata.modified()?,
+                });
+            }
+        }
+        Ok(candidates)
+    }
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| AUDIO_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// `objects/<first two hex digits>/<hash>.<extension>` files of an `AssetStore`
+fn is_store_object(path: &Path) -> bool {
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let shard = path.parent();
+    stem.parse::<ContentHash>().is_ok()
+        && shard.and_then(|shard| shard.file_name()).is_some_and(|shard| stem.starts_with(&*shard.to_string_lossy()))
+        && shard
+            .and_then(|shard| shard.parent())
+            .and_then(|objects| objects.file_name())
+            .is_some_and(|objects| objects == "objects")
+}
+
+async fn remove_asset(asset: &Asset) -> CaResult<()> {
+    tokio::fs::remove_file(asset.path()).await?;
+    for companion in asset.companion_paths() {
+        match tokio::fs::remove_file(companion).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a file aged `age_seconds`, with a sidecar when `sidecar` is set
+    fn write(path: &Path, size: usize, age_seconds: u64, sidecar: bool) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, vec![0u8; size]).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(age_seconds);
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        if sidecar {
+            std::fs::write(Asset::Stored { path: path.to_owned() }.sidecar_path(), b"{}").unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn only_generated_audio_assets_are_collected() {
+        let directory = TempDir::new("gc").unwrap();
+        let root = directory.path();
+        let hash = ContentHash::of_bytes(b"object");
+        let object = root.join("store/objects").join(&hash.as_str()[..2]).join(format!("{}.wav", hash));
+        let excluded = root.join("kept/kept.wav");
+        write(&root.join("conda_executor.py"), 100, 3600, false);
+        write(&root.join("library.json"), 100, 3600, false);
+        write(&root.join("presets/MusicGenSmall/dark.json"), 100, 3600, false);
+        write(&root.join("user.wav"), 100, 3600, false);
+        write(&object, 100, 3600, true);
+        write(&excluded, 100, 3600, true);
+        write(&root.join("out/generated.wav"), 100, 3600, true);
+
+        let report = GarbageCollector::new(vec![root.to_owned()], RetentionPolicy::default().max_age(Duration::from_secs(60)))
+            .exclude(root.join("kept"))
+            .collect()
+            .await
+            .unwrap();
+
+        let root = std::fs::canonicalize(root).unwrap();
+        assert_eq!(report.removed, vec![root.join("out/generated.wav")]);
+        assert!(!root.join("out/generated.wav.json").exists());
+        for kept in ["conda_executor.py", "library.json", "presets/MusicGenSmall/dark.json", "user.wav", "kept/kept.wav"] {
+            assert!(root.join(kept).exists(), "{} was deleted", kept);
+        }
+        assert!(object.exists());
+    }
+
+    #[tokio::test]
+    async fn size_quota_deletes_oldest_and_keeps_favorites() {
+        let directory = TempDir::new("gc").unwrap();
+        let root = directory.path();
+        for (index, age) in [400, 300, 200, 100].into_iter().enumerate() {
+            write(&root.join(format!("out/{}.wav", index)), 1000, age, true);
+        }
+        let library = AssetLibrary::open(root).await.unwrap();
+        // stored non canonical on purpose, the collector must still match the entries
+        let favorite = library
+            .add(&Asset::Stored { path: root.join("out/../out/0.wav") })
+            .await
+            .unwrap();
+        let collected = library
+            .add(&Asset::Stored { path: root.join("out/../out/1.wav") })
+            .await
+            .unwrap();
+        library.set_favorite(favorite, true).await.unwrap();
+
+        // 4 assets of 1002 bytes with their sidecar, the quota fits 2
+        let policy = RetentionPolicy::default().max_total_size(2100).keep_favorites(true);
+        let report = GarbageCollector::new(vec![root.to_owned()], policy)
+            .with_library(library.clone())
+            .collect()
+            .await
+            .unwrap();
+
+        let root = std::fs::canonicalize(root).unwrap();
+        assert_eq!(report.removed, vec![root.join("out/1.wav"), root.join("out/2.wav")]);
+        assert_eq!(report.reclaimed_bytes, 2004);
+        assert_eq!(report.kept_favorites, 1);
+        assert_eq!(report.forgotten, vec![collected]);
+        assert!(library.get(collected).await.is_none());
+        assert!(library.get(favorite).await.is_some());
+    }
+}
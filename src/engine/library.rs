@@ -0,0 +1,502 @@
+use crate::prelude::*;
+
+use super::asset::{Asset, AssetMetadata};
+
+/// Searchable index of assets and their metadata, persisted as `<cache>/library.json`.
+/// Files moved or deleted outside of the library are reconciled by `sync`.
+#[derive(Debug, Clone)]
+pub struct AssetLibrary {
+    index_path: PathBuf,
+    index: Arc<RwLock<LibraryIndex>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct LibraryIndex {
+    next_id: u64,
+    entries: Vec<LibraryEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LibraryEntry {
+    pub id: u64,
+    pub path: PathBuf,
+    pub metadata: AssetMetadata,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    /// User rating, usually in 0..=5
+    #[serde(default)]
+    pub rating: Option<u8>,
+    /// Favorites are kept by the garbage collector, see `RetentionPolicy`
+    #[serde(default)]
+    pub favorite: bool,
+    pub indexed_at: DateTime<Local>,
+    pub fingerprint: Fingerprint,
+}
+
+/// Identifies the content of a file to find it back after it was moved
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    pub size: u64,
+    /// FNV-1a 64 bits hash of the file content
+    pub hash: u64,
+}
+
+/// Conjunction of filters, unset filters match everything
+#[derive(Debug, Clone, Default)]
+pub struct AssetQuery {
+    prompt: Option<String>,
+    model: Option<String>,
+    tags: BTreeSet<String>,
+    created: Option<Range<DateTime<Local>>>,
+    duration: Option<Range<f64>>,
+    min_rating: Option<u8>,
+    favorite: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncReport {
+    /// Entries whose file was found at a new location: (id, new path)
+    pub relocated: Vec<(u64, PathBuf)>,
+    /// Entries whose file could not be found anymore, they were dropped from the index
+    pub removed: Vec<u64>,
+}
+
+impl LibraryEntry {
+    pub fn asset(&self) -> Asset {
+        Asset::Stored { path: self.path.clone() }
+    }
+
+    /// Generation date, falling back to the indexation date
+    pub fn created_at(&self) -> DateTime<Local> {
+        self.metadata.started_at.unwrap_or(self.indexed_at)
+    }
+}
+
+impl Fingerprint {
+    pub async fn of(path: &Path) -> CaResult<Self> {
+        let content = tokio::fs::read(path).await?;
+        let hash = content.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+        Ok(Self {
+            size: content.len() as u64,
+            hash,
+        })
+    }
+}
+
+impl AssetQuery {
+    /// Every whitespace separated word must appear in the prompt, case insensitive
+    pub fn prompt(mut self, text: &str) -> Self {
+        self.prompt = Some(text.to_lowercase());
+        self
+    }
+
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    /// Entries must carry every tag
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.insert(tag.to_string());
+        self
+    }
+
+    pub fn created(mut self, range: Range<DateTime<Local>>) -> Self {
+        self.created = Some(range);
+        self
+    }
+
+    /// Duration range in seconds, entries without a known duration never match
+    pub fn duration(mut self, range: Range<f64>) -> Self {
+        self.duration = Some(range);
+        self
+    }
+
+    pub fn min_rating(mut self, rating: u8) -> Self {
+        self.min_rating = Some(rating);
+        self
+    }
+
+    pub fn favorite(mut self, favorite: bool) -> Self {
+        self.favorite = Some(favorite);
+        self
+    }
+
+    pub fn matches(&self, entry: &LibraryEntry) -> bool {
+        let prompt = entry.metadata.prompt.as_deref().unwrap_or_default().to_lowercase();
+        self.prompt
+            .as_ref()
+            .is_none_or(|text| text.split_whitespace().all(|word| prompt.contains(word)))
+            && self
+                .model
+                .as_ref()
+                .is_none_or(|model| entry.metadata.model.as_ref() == Some(model))
+            && self.tags.is_subset(&entry.tags)
+            && self
+                .created
+                .as_ref()
+                .is_none_or(|range| range.contains(&entry.created_at()))
+            && self
+                .duration
+                .as_ref()
+                .is_none_or(|range| entry.metadata.duration.is_some_and(|duration| range.contains(&duration)))
+            && self
+                .min_rating
+                .is_none_or(|rating| entry.rating.is_some_and(|entry_rating| entry_rating >= rating))
+            && self.favorite.is_none_or(|favorite| entry.favorite == favorite)
+    }
+}
+
+impl AssetLibrary {
+    pub async fn open(cache_directory: &Path) -> CaResult<Self> {
+        let index_path = cache_directory.join("library.json");
+        let index = match tokio::fs::read(&index_path).await {
+            Ok(raw) => serde_json::from_slice(&raw)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => LibraryIndex::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            index_path,
+            index: Arc::new(RwLock::new(index)),
+        })
+    }
+
+    /// Index an asset with its metadata sidecar, re-indexing an already known path keeps its tags, rating and favorite flag
+    pub async fn add(&self, asset: &Asset) -> CaResult<u64> {
+        let metadata = asset.metadata().await?.unwrap_or_default();
+        let fingerprint = Fingerprint::of(asset.path()).await?;
+        let mut index = self.index.write().await;
+        let id = match index.entries.iter_mut().find(|entry| entry.path == asset.path()) {
+            Some(entry) => {
+                entry.metadata = metadata;
+                entry.fingerprint = fingerprint;
+                entry.id
+            }
+            None => {
+                let id = index.next_id;
+                index.next_id += 1;
+                index.entries.push(LibraryEntry {
+                    id,
+                    path: asset.path().to_owned(),
+                    metadata,
+                    tags: BTreeSet::new(),
+                    rating: None,
+                    favorite: false,
+                    indexed_at: Local::now(),
+                    fingerprint,
+                });
+                id
+            }
+        };
+        self.persist(&index).await?;
+        Ok(id)
+    }
+
+    pub async fn get(&self, id: u64) -> Option<LibraryEntry> {
+        self.index.read().await.entries.iter().find(|entry| entry.id == id).cloned()
+    }
+
+    /// Matching entries, most recent first
+    pub async fn query(&self, query: &AssetQuery) -> Vec<LibraryEntry> {
+        let mut entries: Vec<LibraryEntry> = self
+            .index
+            .read()
+            .await
+            .entries
+            .iter()
+            .filter(|entry| query.matches(entry))
+            .cloned()
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.created_at()));
+        entries
+    }
+
+    pub async fn set_tags(&self, id: u64, tags: BTreeSet<String>) -> CaResult<()> {
+        self.update(id, |entry| entry.tags = tags).await
+    }
+
+    pub async fn set_rating(&self, id: u64, rating: Option<u8>) -> CaResult<()> {
+        self.update(id, |entry| entry.rating = rating).await
+    }
+
+    pub async fn set_favorite(&self, id: u64, favorite: bool) -> CaResult<()> {
+        self.update(id, |entry| entry.favorite = favorite).await
+    }
+
+    /// Move the asset file and its companion files, the index follows the file
+    pub async fn move_asset(&self, id: u64, destination: &Path) -> CaResult<Asset> {
+        let mut index = self.index.write().await;
+        let entry = index
+            .entries
+            .iter_mut()
+            .find(|entry| entry.id == id)
+            .ok_or(CaError::AssetNotFound(id as usize))?;
+        let source = entry.asset();
+        let destination = Asset::Stored { path: destination.to_owned() };
+        tokio::fs::rename(source.path(), destination.path()).await?;
+        for (from, to) in source.companion_paths().into_iter().zip(destination.companion_paths()) {
+            if tokio::fs::try_exists(&from).await? {
+                tokio::fs::rename(from, to).await?;
+            }
+        }
+        entry.path = destination.path().to_owned();
+        self.persist(&index).await?;
+        Ok(destination)
+    }
+
+    /// Drop an entry from the index, deleting the file and its companion files when `delete_file` is set
+    pub async fn remove(&self, id: u64, delete_file: bool) -> CaResult<()> {
+        let mut index = self.index.write().await;
+        let position = index
+            .entries
+            .iter()
+            .position(|entry| entry.id == id)
+            .ok_or(CaError::AssetNotFound(id as usize))?;
+        let entry = index.entries.remove(position);
+        if delete_file {
+            let asset = entry.asset();
+            for path in std::iter::once(asset.path().to_owned()).chain(asset.companion_paths()) {
+                match tokio::fs::remove_file(path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        self.persist(&index).await
+    }
+
+    /// Drop the entries of files deleted outside of the library, returns the ids that were indexed
+    pub async fn forget(&self, ids: &HashSet<u64>) -> CaResult<Vec<u64>> {
+        let mut index = self.index.write().await;
+        let forgotten: Vec<u64> = index
+            .entries
+            .iter()
+            .filter(|entry| ids.contains(&entry.id))
+            .map(|entry| entry.id)
+            .collect();
+        if !forgotten.is_empty() {
+            index.entries.retain(|entry| !ids.contains(&entry.id));
+            self.persist(&index).await?;
+        }
+        Ok(forgotten)
+    }
+
+    /// Reconcile the index with the file system: entries whose file is missing are looked
+    /// up by fingerprint in `search_directories` (recursively) and dropped when not found.
+    pub async fn sync(&self, search_directories: &[PathBuf]) -> CaResult<SyncReport> {
+        let mut index = self.index.write().await;
+        let mut report = SyncReport::default();
+        let mut missing = vec![];
+        for (position, entry) in index.entries.iter().enumerate() {
+            if !tokio::fs::try_exists(&entry.path).await? {
+                missing.push(position);
+            }
+        }
+        if missing.is_empty() {
+            return Ok(report);
+        }
+
+        let mut candidates = vec![];
+        let mut directories: Vec<PathBuf> = search_directories.to_vec();
+        while let Some(directory) = directories.pop() {
+            let mut entries = match tokio::fs::read_dir(&directory).await {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!(directory = ?directory, "library sync skipped a directory: {}", e);
+                    continue;
+                }
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    directories.push(entry.path());
+                } else if file_type.is_file() {
+                    candidates.push((entry.path(), entry.metadata().await?.len()));
+                }
+            }
+        }
+
+        // a file already indexed can't be the new location of another entry
+        let mut indexed: HashSet<PathBuf> = index.entries.iter().map(|entry| entry.path.clone()).collect();
+        for position in missing.iter().copied() {
+            let entry = &index.entries[position];
+            let mut found = None;
+            for (path, size) in candidates.iter() {
+                if *size == entry.fingerprint.size
+                    && !indexed.contains(path)
+                    && Fingerprint::of(path).await.is_ok_and(|fingerprint| fingerprint == entry.fingerprint)
+                {
+                    found = Some(path.clone());
+                    break;
+                }
+            }
+            match found {
+                Some(path) => {
+                    indexed.insert(path.clone());
+                    report.relocated.push((entry.id, path.clone()));
+                    index.entries[position].path = path;
+                }
+                None => report.removed.push(entry.id),
+            }
+        }
+        index.entries.retain(|entry| !report.removed.contains(&entry.id));
+        self.persist(&index).await?;
+        Ok(report)
+    }
+
+    async fn update<F: FnOnce(&mut LibraryEntry)>(&self, id: u64, update: F) -> CaResult<()> {
+        let mut index = self.index.write().await;
+        let entry = index
+            .entries
+            .iter_mut()
+            .find(|entry| entry.id == id)
+            .ok_or(CaError::AssetNotFound(id as usize))?;
+        update(entry);
+        self.persist(&index).await
+    }
+
+    /// Write the index to a temporary file then rename it, so a crash never leaves a truncated index
+    async fn persist(&self, index: &LibraryIndex) -> CaResult<()> {
+        if let Some(parent) = self.index_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp = self.index_path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(index)?).await?;
+        tokio::fs::rename(&tmp, &self.index_path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn asset(directory: &Path, name: &str, content: &[u8], metadata: AssetMetadata) -> Asset {
+        let asset = Asset::Stored { path: directory.join(name) };
+        tokio::fs::write(asset.path(), content).await.unwrap();
+        asset.write_metadata(&metadata).await.unwrap();
+        asset
+    }
+
+    fn entry(metadata: AssetMetadata) -> LibraryEntry {
+        LibraryEntry {
+            id: 0,
+            path: PathBuf::from("clip.wav"),
+            metadata,
+            tags: BTreeSet::from(["drums".to_string(), "loop".to_string()]),
+            rating: Some(4),
+            favorite: false,
+            indexed_at: Local::now(),
+            fingerprint: Fingerprint { size: 0, hash: 0 },
+        }
+    }
+
+    #[test]
+    fn queries_combine_every_filter() {
+        let started_at = Local::now() - chrono::Duration::days(2);
+        let entry = entry(AssetMetadata {
+            model: Some("MusicGenSmall".to_string()),
+            prompt: Some("Dark Techno kick loop".to_string()),
+            duration: Some(8.0),
+            started_at: Some(started_at),
+            ..Default::default()
+        });
+        let day = chrono::Duration::days(1);
+        assert!(AssetQuery::default().matches(&entry));
+        assert!(AssetQuery::default()
+            .prompt("kick  techno")
+            .model("MusicGenSmall")
+            .tag("drums")
+            .created(started_at - day..started_at + day)
+            .duration(5.0..10.0)
+            .min_rating(4)
+            .favorite(false)
+            .matches(&entry));
+        assert!(!AssetQuery::default().prompt("ambient").matches(&entry));
+        assert!(!AssetQuery::default().model("AudioGen").matches(&entry));
+        assert!(!AssetQuery::default().tag("vocals").matches(&entry));
+        assert!(!AssetQuery::default().created(started_at + day..started_at + day * 2).matches(&entry));
+        assert!(!AssetQuery::default().duration(10.0..20.0).matches(&entry));
+        assert!(!AssetQuery::default().min_rating(5).matches(&entry));
+        assert!(!AssetQuery::default().favorite(true).matches(&entry));
+    }
+
+    #[test]
+    fn entries_without_duration_or_rating_never_match_those_filters() {
+        let mut entry = entry(AssetMetadata::default());
+        entry.rating = None;
+        assert!(!AssetQuery::default().duration(0.0..100.0).matches(&entry));
+        assert!(!AssetQuery::default().min_rating(0).matches(&entry));
+    }
+
+    #[tokio::test]
+    async fn index_is_persisted_across_open() {
+        let directory = TempDir::new("crovai_library").unwrap();
+        let cache = directory.path().join("cache");
+        let library = AssetLibrary::open(&cache).await.unwrap();
+        let metadata = AssetMetadata {
+            prompt: Some("warm pads".to_string()),
+            ..Default::default()
+        };
+        let id = library.add(&asset(directory.path(), "a.wav", b"a", metadata).await).await.unwrap();
+        library.set_tags(id, BTreeSet::from(["pads".to_string()])).await.unwrap();
+        library.set_rating(id, Some(3)).await.unwrap();
+        library.set_favorite(id, true).await.unwrap();
+
+        let reopened = AssetLibrary::open(&cache).await.unwrap();
+        assert_eq!(reopened.get(id).await, library.get(id).await);
+        let found = reopened.query(&AssetQuery::default().prompt("pads").tag("pads").favorite(true)).await;
+        assert_eq!(found.len(), 1);
+        // ids are never reused after a reopen
+        let other = reopened.add(&asset(directory.path(), "b.wav", b"b", AssetMetadata::default()).await).await;
+        assert_ne!(other.unwrap(), id);
+    }
+
+    #[tokio::test]
+    async fn move_and_remove_carry_the_companion_files() {
+        let directory = TempDir::new("crovai_library").unwrap();
+        let library = AssetLibrary::open(directory.path()).await.unwrap();
+        let source = asset(directory.path(), "a.wav", b"a", AssetMetadata::default()).await;
+        tokio::fs::write(source.peaks_path(), b"peaks").await.unwrap();
+        let id = library.add(&source).await.unwrap();
+
+        let moved = library.move_asset(id, &directory.path().join("b.wav")).await.unwrap();
+        assert_eq!(library.get(id).await.unwrap().path, moved.path());
+        for (from, to) in source.companion_paths().iter().zip(moved.companion_paths()) {
+            assert!(!from.exists());
+            assert!(to.exists());
+        }
+
+        library.remove(id, true).await.unwrap();
+        assert!(library.get(id).await.is_none());
+        assert!(!moved.path().exists());
+        assert!(moved.companion_paths().iter().all(|path| !path.exists()));
+    }
+
+    #[tokio::test]
+    async fn sync_relocates_moved_files_and_drops_deleted_ones() {
+        let directory = TempDir::new("crovai_library").unwrap();
+        let library = AssetLibrary::open(&directory.path().join("cache")).await.unwrap();
+        let moved = asset(directory.path(), "moved.wav", b"moved content", AssetMetadata::default()).await;
+        let deleted = asset(directory.path(), "deleted.wav", b"deleted content", AssetMetadata::default()).await;
+        let kept = asset(directory.path(), "kept.wav", b"kept content", AssetMetadata::default()).await;
+        let moved_id = library.add(&moved).await.unwrap();
+        let deleted_id = library.add(&deleted).await.unwrap();
+        let kept_id = library.add(&kept).await.unwrap();
+
+        let subdirectory = directory.path().join("renamed");
+        tokio::fs::create_dir(&subdirectory).await.unwrap();
+        let new_path = subdirectory.join("renamed.wav");
+        tokio::fs::rename(moved.path(), &new_path).await.unwrap();
+        tokio::fs::remove_file(deleted.path()).await.unwrap();
+
+        let report = library.sync(&[directory.path().to_owned()]).await.unwrap();
+        assert_eq!(report.relocated, [(moved_id, new_path.clone())]);
+        assert_eq!(report.removed, [deleted_id]);
+        assert_eq!(library.get(moved_id).await.unwrap().path, new_path);
+        assert!(library.get(deleted_id).await.is_none());
+        assert_eq!(library.get(kept_id).await.unwrap().path, kept.path());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::prelude::*;
+
+use crate::audio::export::{export, ExportOptions, SampleEncoding};
+use crate::audio::wav::{decode_wav_file_async, deinterleave, AudioFormat};
+
+use super::asset::{Asset, AssetMetadata};
+use super::conda::CondaExecutor;
+use super::diffusion::{AudioAssetOpt, DiffusionModelOpt, DiffusionModelTemplate, FloatOpt};
+use super::params::supports_continuation;
+use super::prompt::HostContext;
+
+/// Configuration of a long form generation.
+/// The model is run in windows of `window_seconds` (the template `SECONDS_TOTAL`),
+/// each window continuing from the last `overlap_seconds` of the previous one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LongFormOpts {
+    pub seconds_total: f32,
+    pub window_seconds: f32,
+    pub overlap_seconds: f32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LongFormProgress {
+    /// Number of windows generated so far
+    pub window: usize,
+    pub windows: usize,
+}
+
+impl Default for LongFormOpts {
+    fn default() -> Self {
+        Self {
+            seconds_total: 60.0,
+            window_seconds: 30.0,
+            overlap_seconds: 10.0,
+        }
+    }
+}
+
+impl LongFormOpts {
+    /// Number of model windows required to cover `seconds_total`
+    pub fn windows(&self) -> CaResult<usize> {
+        if self.window_seconds <= 0.0 || self.seconds_total <= 0.0 {
+            return Err(CaError::InvalidLongFormOpts("durations must be positive".to_string()));
+        }
+        if self.overlap_seconds < 0.0 || self.overlap_seconds >= self.window_seconds {
+            return Err(CaError::InvalidLongFormOpts(
+                "overlap must be in [0, window_seconds)".to_string(),
+            ));
+        }
+        let remaining = (self.seconds_total - self.window_seconds).max(0.0);
+        let step = self.window_seconds - self.overlap_seconds;
+        Ok(1 + (remaining / step).ceil() as usize)
+    }
+}
+
+impl CondaExecutor {
+    /// Generate an asset longer than the model window by chaining continuations, see `append_continuation`.
+    /// Only models reading `CONTINUATION_SAMPLE` (the MusicGen family) without melody conditioning are accepted.
+    /// Prompts are expanded with `context` for every window.
+    pub async fn process_long_form_diffusion_model<F: FnMut(LongFormProgress)>(
+        &self,
+        template: DiffusionModelTemplate,
+        output_directory: PathBuf,
+        opts: LongFormOpts,
+        context: &HostContext,
+        mut on_progress: F,
+    ) -> CaResult<Asset> {
+        let windows = opts.windows()?;
+        check_continuation(&template)?;
+        let started_at = Local::now();
+        let started = std::time::Instant::now();
+        let working_directory = TempDir::new("crovai_long_form")?;
+
+        let mut stitched: Option<(u32, AudioFormat, Vec<f32>)> = None;
+        let mut previous: Option<Asset> = None;
+        let mut metadata = None;
+        for window in 0..windows {
+            let mut window_template = template.clone();
+            match window_template
+                .options
+                .get_mut("SECONDS_TOTAL")
+                .and_then(|opt| opt.as_float_value_mut())
+            {
+                Some(seconds) => *seconds = opts.window_seconds,
+                None => {
+                    return Err(CaError::InvalidLongFormOpts(
+                        "template has no SECONDS_TOTAL value".to_string(),
+                    ))
+                }
+            }
+            if let Some(previous) = previous.as_ref() {
+                window_template.options.insert(
+                    "CONTINUATION_SAMPLE".to_string(),
+                    DiffusionModelOpt::AudioAsset(AudioAssetOpt {
+                        hidden: true,
+                        ..previous.into()
+                    }),
+                );
+                window_template.options.insert(
+                    "CONTINUATION_SECONDS".to_string(),
+                    DiffusionModelOpt::Float(FloatOpt {
+                        value: Some(opts.overlap_seconds),
+                        hidden: true,
+                        ..Default::default()
+                    }),
+                );
+            }
+
+            let asset = self
+                .process_diffusion_model_with_context(window_template, working_directory.path().to_owned(), context)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or(CaError::Empty)?;
+
+            if metadata.is_none() {
+                metadata = asset.metadata().await?;
+            }
+            let decoded = decode_wav_file_async(asset.path()).await?;
+            let samples = decoded.sample.interleave();
+            stitched = Some(match stitched {
+                None => (decoded.sample_rate, decoded.format, samples),
+                Some((sample_rate, format, mut stitched_samples)) => {
+                    if decoded.sample_rate != sample_rate || decoded.format.channels != format.channels {
+                        return Err(CaError::UnsupportedFormat(
+                            "windows differ in sample rate or channels".to_string(),
+                        ));
+                    }
+                    let prompt = (opts.overlap_seconds * sample_rate as f32) as usize;
+                    let fade = (SEAM_FADE_SECONDS * sample_rate as f32) as usize;
+                    append_continuation(&mut stitched_samples, &samples, prompt, fade, format.channels as usize);
+                    (sample_rate, format, stitched_samples)
+                }
+            });
+            previous = Some(asset);
+            on_progress(LongFormProgress {
+                window: window + 1,
+                windows,
+            });
+        }
+
+        let (sample_rate, format, mut samples) = stitched.ok_or(CaError::Empty)?;
+        let frames = (opts.seconds_total * sample_rate as f32) as usize;
+        samples.truncate(frames * format.channels as usize);
+
+        let path = output_directory.join(format!("long_form_{}", Asset::unique_file_name("wav")));
+        let options = ExportOptions::wav(SampleEncoding::from_format(format));
+        let asset = export(deinterleave(&samples, format.channels), sample_rate, &path, &options).await?;
+
+        let mut metadata = metadata.unwrap_or_else(|| AssetMetadata::from_template(&template));
+        metadata.started_at = Some(started_at);
+        metadata.finished_at = Some(Local::now());
+        metadata.generation_time = Some(started.elapsed().as_secs_f64());
+        metadata.read_format(asset.path()).await?;
+        asset.write_metadata(&metadata).await?;
+        Ok(asset)
+    }
+}
+
+/// Length of the crossfade hiding the seam between two windows
+pub const SEAM_FADE_SECONDS: f32 = 0.05;
+
+/// Reject templates whose model can't continue a window or whose conditioning would be lost after the first one
+fn check_continuation(template: &DiffusionModelTemplate) -> CaResult<()> {
+    let model = template
+        .options
+        .get("MODEL")
+        .and_then(|opt| opt.as_string_ref())
+        .and_then(|opt| opt.value.clone())
+        .unwrap_or_default();
+    if !supports_continuation(&model) {
+        return Err(CaError::InvalidLongFormOpts(format!(
+            "{:?} does not support continuation",
+            model
+        )));
+    }
+    // continuations are not conditioned on the melody, it would only drive the first window
+    let melody = template
+        .options
+        .get("CONDITION_SAMPLE")
+        .and_then(|opt| opt.as_audioasset_value_ref())
+        .is_some();
+    if melody {
+        return Err(CaError::InvalidLongFormOpts(
+            "melody conditioning (CONDITION_SAMPLE) is not supported by long form generation".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Append the continuation window `next` to `output`.
+/// `next` starts with `prompt` frames re-generated from the tail of `output`, they are dropped
+/// except for the last `fade` ones which are crossfaded with the tail of `output` to hide the seam.
+/// Both sides of the crossfade carry the same material, a linear curve keeps its level constant
+/// where an equal-power one would boost it by 3 dB.
+pub fn append_continuation(output: &mut Vec<f32>, next: &[f32], prompt: usize, fade: usize, channels: usize) {
+    let prompt = std::cmp::min(prompt, next.len() / channels);
+    let fade = std::cmp::min(fade, std::cmp::min(prompt, output.len() / channels));
+    let start = output.len() - fade * channels;
+    let next = &next[(prompt - fade) * channels..];
+    for frame in 0..fade {
+        let fade_in = (frame as f32 + 0.5) / fade as f32;
+        for channel in 0..channels {
+            let idx = frame * channels + channel;
+            output[start + idx] = output[start + idx] * (1.0 - fade_in) + next[idx] * fade_in;
+        }
+    }
+    output.extend_from_slice(&next[fade * channels..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_cover_the_requested_duration() {
+        let opts = LongFormOpts {
+            seconds_total: 70.0,
+            window_seconds: 30.0,
+            overlap_seconds: 10.0,
+        };
+        assert_eq!(opts.windows().unwrap(), 3);
+        let opts = LongFormOpts {
+            overlap_seconds: 30.0,
+            ..opts
+        };
+        assert!(opts.windows().is_err());
+    }
+
+    #[test]
+    fn continuations_replace_their_prompt_region() {
+        let mut output = vec![1.0, -1.0, 2.0, -2.0, 3.0, -3.0];
+        let next = vec![9.0, -9.0, 3.0, -3.0, 4.0, -4.0, 5.0, -5.0];
+        append_continuation(&mut output, &next, 2, 1, 2);
+        assert_eq!(output, [1.0, -1.0, 2.0, -2.0, 3.0, -3.0, 4.0, -4.0, 5.0, -5.0]);
+    }
+
+    #[test]
+    fn seams_over_identical_material_keep_their_level() {
+        // the prompt region of the continuation is a copy of the tail of the previous window
+        let mut output = vec![0.5; 1000];
+        let next = vec![0.5; 1000];
+        append_continuation(&mut output, &next, 400, 100, 1);
+        assert_eq!(output.len(), 1600);
+        assert!(output.iter().all(|sample| (sample - 0.5).abs() < 1e-6));
+    }
+
+    fn template(model: &str, melody: Option<&str>) -> DiffusionModelTemplate {
+        serde_json::from_value(serde_json::json!({
+            "options": {
+                "MODEL": { "hidden": true, "kind": "String", "value": model },
+                "CONDITION_SAMPLE": { "kind": "AudioAsset", "value": melody }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_musicgen_models_without_melody_are_continued() {
+        assert!(check_continuation(&template("MusicGenSmall", None)).is_ok());
+        assert!(check_continuation(&template("MusicGenMelody", None)).is_ok());
+        assert!(check_continuation(&template("MusicGenMelody", Some("melody.wav"))).is_err());
+        assert!(check_continuation(&template("AudioGen", None)).is_err());
+        assert!(check_continuation(&template("StableAudioOpen", None)).is_err());
+    }
+}
@@ -1,4 +1,12 @@
 pub mod diffusion;
 pub mod asset;
 pub mod conda;
-pub mod generic;
\ No newline at end of file
+pub mod generic;
+pub mod long_form;
+pub mod preset;
+pub mod params;
+pub mod automation;
+pub mod prompt;
+pub mod library;
+pub mod store;
+pub mod gc;
\ No newline at end of file
@@ -0,0 +1,487 @@
+use crate::prelude::*;
+
+use super::diffusion::{
+    AudioAssetOpt, DiffusionModelOpt, DiffusionModelTemplate, EnumOpt, FloatOpt, IntOpt, SeedOpt, StringOpt,
+};
+
+const MUSICGEN_MODELS: &[&str] = &[
+    "MusicGenSmall",
+    "MusicGenMedium",
+    "MusicGenLarge",
+    "MusicGenMelody",
+    "MusicGenSongStarter",
+];
+const MELODY_MODELS: &[&str] = &["MusicGenMelody", "MusicGenSongStarter"];
+const STABLE_AUDIO_MODELS: &[&str] = &["StableAudioOpen"];
+
+/// Models whose runner reads `CONTINUATION_SAMPLE`
+pub fn supports_continuation(model: &str) -> bool {
+    MUSICGEN_MODELS.contains(&model)
+}
+
+/// Reason a template can't be converted into typed parameters
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ParamError {
+    #[error("{key}: option is missing")]
+    Missing { key: String },
+    #[error("{key}: expected a {expected} option, found {found}")]
+    WrongKind {
+        key: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[error("{key}: option has no value")]
+    NoValue { key: String },
+    #[error("{key}: unexpected value {value:?}")]
+    InvalidValue { key: String, value: String },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum Decoder {
+    #[default]
+    Encodec,
+    MultibandDiffusion,
+}
+
+/// Parameters of every MusicGen model
+#[derive(Debug, Clone, PartialEq)]
+pub struct MusicGenParams {
+    pub model: String,
+    pub point: String,
+    pub prompt: String,
+    pub seconds_total: f32,
+    pub decoder: Decoder,
+    /// `None` lets the worker pick the device
+    pub device: Option<String>,
+    /// `None` draws a random seed on every generation
+    pub seed: Option<u64>,
+}
+
+/// Parameters of the melody conditioned MusicGen models
+#[derive(Debug, Clone, PartialEq)]
+pub struct MelodyParams {
+    pub musicgen: MusicGenParams,
+    pub condition_sample: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StableAudioParams {
+    pub model: String,
+    pub point: String,
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    pub seconds_start: f32,
+    pub seconds_total: f32,
+    pub steps: i64,
+    pub cfg_scale: f32,
+    pub sigma_min: f32,
+    pub sigma_max: f32,
+    pub sampler_type: String,
+    pub device: Option<String>,
+    pub seed: Option<u64>,
+}
+
+impl Decoder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decoder::Encodec => "encodec",
+            Decoder::MultibandDiffusion => "multiband_diffusion",
+        }
+    }
+}
+
+impl std::str::FromStr for Decoder {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "encodec" => Ok(Decoder::Encodec),
+            "multiband_diffusion" => Ok(Decoder::MultibandDiffusion),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Typed parameters written into the template of their model.
+/// The conversion into a template only carries the values, like an override or a preset: use `apply_to`
+/// to merge them over the template returned by the worker so ranges, choices, required flags, metadata and rules are kept.
+pub trait TemplateParams: Into<DiffusionModelTemplate> {
+    /// Model whose template the parameters apply to
+    fn model(&self) -> &str;
+
+    /// Write the parameters into `template`, which must be the template of `model()`
+    fn apply_to(self, template: &DiffusionModelTemplate) -> Result<DiffusionModelTemplate, ParamError> {
+        let model = self.model().to_string();
+        let template_model = OptionReader(template).string("MODEL")?;
+        if template_model != model {
+            return Err(ParamError::InvalidValue {
+                key: "MODEL".to_string(),
+                value: model,
+            });
+        }
+        let (merged, report) = template.apply_overrides(&self.into());
+        if let Some(mismatch) = report.kind_mismatches.first() {
+            return Err(ParamError::WrongKind {
+                key: mismatch.key.clone(),
+                expected: kind_name(&mismatch.expected),
+                found: kind_name(&mismatch.found),
+            });
+        }
+        if let Some(key) = report.unknown_keys.first() {
+            return Err(ParamError::Missing { key: key.clone() });
+        }
+        Ok(merged)
+    }
+}
+
+fn kind_name(kind: &str) -> &'static str {
+    ["Int", "Float", "String", "Bool", "Enum", "AudioAsset", "Seed"]
+        .into_iter()
+        .find(|name| *name == kind)
+        .unwrap_or("unknown")
+}
+
+/// Typed accessors over the options of a template
+struct OptionReader<'a>(&'a DiffusionModelTemplate);
+
+impl<'a> OptionReader<'a> {
+    fn get(&self, key: &str) -> Result<&'a DiffusionModelOpt, ParamError> {
+        self.0.options.get(key).ok_or_else(|| ParamError::Missing { key: key.to_string() })
+    }
+
+    fn value<T>(
+        &self,
+        key: &str,
+        expected: &'static str,
+        read: impl Fn(&DiffusionModelOpt) -> Option<Option<T>>,
+    ) -> Result<Option<T>, ParamError> {
+        let opt = self.get(key)?;
+        read(opt).ok_or_else(|| ParamError::WrongKind {
+            key: key.to_string(),
+            expected,
+            found: opt.kind(),
+        })
+    }
+
+    fn required<T>(
+        &self,
+        key: &str,
+        expected: &'static str,
+        read: impl Fn(&DiffusionModelOpt) -> Option<Option<T>>,
+    ) -> Result<T, ParamError> {
+        self.value(key, expected, read)?
+            .ok_or_else(|| ParamError::NoValue { key: key.to_string() })
+    }
+
+    fn string(&self, key: &str) -> Result<String, ParamError> {
+        self.required(key, "String", |opt| opt.as_string_ref().map(|opt| opt.value.clone()))
+    }
+
+    fn optional_string(&self, key: &str) -> Result<Option<String>, ParamError> {
+        match self.0.options.contains_key(key) {
+            true => self.value(key, "String", |opt| opt.as_string_ref().map(|opt| opt.value.clone())),
+            false => Ok(None),
+        }
+    }
+
+    fn float(&self, key: &str) -> Result<f32, ParamError> {
+        self.required(key, "Float", |opt| opt.as_float_ref().map(|opt| opt.value))
+    }
+
+    fn int(&self, key: &str) -> Result<i64, ParamError> {
+        self.required(key, "Int", |opt| opt.as_int_ref().map(|opt| opt.value))
+    }
+
+    fn enum_value(&self, key: &str) -> Result<String, ParamError> {
+        self.required(key, "Enum", |opt| opt.as_enum_ref().map(|opt| opt.value.clone()))
+    }
+
+    fn audio_asset(&self, key: &str) -> Result<Option<PathBuf>, ParamError> {
+        self.value(key, "AudioAsset", |opt| opt.as_audioasset_ref().map(|opt| opt.value.clone()))
+    }
+
+    fn seed(&self, key: &str) -> Result<Option<u64>, ParamError> {
+        match self.0.options.contains_key(key) {
+            true => self.value(key, "Seed", |opt| {
+                opt.as_seed_ref().map(|opt| opt.value.filter(|_| !opt.random))
+            }),
+            false => Ok(None),
+        }
+    }
+
+    /// `MODEL` value, checked against the models accepted by the parameter struct
+    fn model(&self, accepted: &[&str]) -> Result<String, ParamError> {
+        let model = self.string("MODEL")?;
+        if !accepted.contains(&model.as_str()) {
+            return Err(ParamError::InvalidValue {
+                key: "MODEL".to_string(),
+                value: model,
+            });
+        }
+        Ok(model)
+    }
+
+    fn device(&self) -> Result<Option<String>, ParamError> {
+        Ok(self.optional_string("DEVICE")?.filter(|device| device != "auto"))
+    }
+}
+
+fn overrides<const N: usize>(options: [(&str, DiffusionModelOpt); N]) -> DiffusionModelTemplate {
+    DiffusionModelTemplate {
+        options: options.into_iter().map(|(key, opt)| (key.to_string(), opt)).collect(),
+        ..Default::default()
+    }
+}
+
+fn string(value: String) -> DiffusionModelOpt {
+    DiffusionModelOpt::String(StringOpt {
+        value: Some(value),
+        ..Default::default()
+    })
+}
+
+fn hidden_string(value: String) -> DiffusionModelOpt {
+    DiffusionModelOpt::String(StringOpt {
+        value: Some(value),
+        hidden: true,
+        ..Default::default()
+    })
+}
+
+fn float(value: f32) -> DiffusionModelOpt {
+    DiffusionModelOpt::Float(FloatOpt {
+        value: Some(value),
+        ..Default::default()
+    })
+}
+
+fn seed(value: Option<u64>) -> DiffusionModelOpt {
+    DiffusionModelOpt::Seed(value.map_or_else(SeedOpt::random, SeedOpt::fixed))
+}
+
+fn device(value: Option<String>) -> DiffusionModelOpt {
+    string(value.unwrap_or_else(|| "auto".to_string()))
+}
+
+impl TryFrom<DiffusionModelTemplate> for MusicGenParams {
+    type Error = ParamError;
+
+    fn try_from(template: DiffusionModelTemplate) -> Result<Self, Self::Error> {
+        let options = OptionReader(&template);
+        let decoder = match options.optional_string("DECODER")? {
+            Some(decoder) => decoder.parse().map_err(|_| ParamError::InvalidValue {
+                key: "DECODER".to_string(),
+                value: decoder,
+            })?,
+            None => Decoder::default(),
+        };
+        Ok(Self {
+            model: options.model(MUSICGEN_MODELS)?,
+            point: options.string("POINT")?,
+            prompt: options.string("CONDITION_PROMPT")?,
+            seconds_total: options.float("SECONDS_TOTAL")?,
+            decoder,
+            device: options.device()?,
+            seed: options.seed("SEED")?,
+        })
+    }
+}
+
+impl TryFrom<DiffusionModelTemplate> for MelodyParams {
+    type Error = ParamError;
+
+    fn try_from(template: DiffusionModelTemplate) -> Result<Self, Self::Error> {
+        let options = OptionReader(&template);
+        options.model(MELODY_MODELS)?;
+        let condition_sample = options.audio_asset("CONDITION_SAMPLE")?;
+        Ok(Self {
+            musicgen: template.try_into()?,
+            condition_sample,
+        })
+    }
+}
+
+impl TryFrom<DiffusionModelTemplate> for StableAudioParams {
+    type Error = ParamError;
+
+    fn try_from(template: DiffusionModelTemplate) -> Result<Self, Self::Error> {
+        let options = OptionReader(&template);
+        Ok(Self {
+            model: options.model(STABLE_AUDIO_MODELS)?,
+            point: options.string("POINT")?,
+            prompt: options.string("CONDITION_PROMPT")?,
+            negative_prompt: options
+                .optional_string("NEGATIVE_PROMPT")?
+                .filter(|prompt| !prompt.is_empty()),
+            seconds_start: options.float("SECONDS_START")?,
+            seconds_total: options.float("SECONDS_TOTAL")?,
+            steps: options.int("STEPS")?,
+            cfg_scale: options.float("CFG_SCALE")?,
+            sigma_min: options.float("SIGMA_MIN")?,
+            sigma_max: options.float("SIGMA_MAX")?,
+            sampler_type: options.enum_value("SAMPLER_TYPE")?,
+            device: options.device()?,
+            seed: options.seed("SEED")?,
+        })
+    }
+}
+
+impl TemplateParams for MusicGenParams {
+    fn model(&self) -> &str {
+        &self.model
+    }
+}
+
+impl TemplateParams for MelodyParams {
+    fn model(&self) -> &str {
+        &self.musicgen.model
+    }
+}
+
+impl TemplateParams for StableAudioParams {
+    fn model(&self) -> &str {
+        &self.model
+    }
+}
+
+impl From<MusicGenParams> for DiffusionModelTemplate {
+    fn from(params: MusicGenParams) -> Self {
+        overrides([
+            ("MODEL", hidden_string(params.model)),
+            ("POINT", string(params.point)),
+            ("CONDITION_PROMPT", string(params.prompt)),
+            ("SECONDS_TOTAL", float(params.seconds_total)),
+            ("DECODER", string(params.decoder.as_str().to_string())),
+            ("DEVICE", device(params.device)),
+            ("SEED", seed(params.seed)),
+        ])
+    }
+}
+
+impl From<MelodyParams> for DiffusionModelTemplate {
+    fn from(params: MelodyParams) -> Self {
+        let mut template = DiffusionModelTemplate::from(params.musicgen);
+        template.options.insert(
+            "CONDITION_SAMPLE".to_string(),
+            DiffusionModelOpt::AudioAsset(AudioAssetOpt {
+                value: params.condition_sample,
+                ..Default::default()
+            }),
+        );
+        template
+    }
+}
+
+impl From<StableAudioParams> for DiffusionModelTemplate {
+    fn from(params: StableAudioParams) -> Self {
+        overrides([
+            ("MODEL", hidden_string(params.model)),
+            ("POINT", string(params.point)),
+            ("CONDITION_PROMPT", string(params.prompt)),
+            ("NEGATIVE_PROMPT", string(params.negative_prompt.unwrap_or_default())),
+            ("SECONDS_START", float(params.seconds_start)),
+            ("SECONDS_TOTAL", float(params.seconds_total)),
+            (
+                "STEPS",
+                DiffusionModelOpt::Int(IntOpt {
+                    value: Some(params.steps),
+                    ..Default::default()
+                }),
+            ),
+            ("CFG_SCALE", float(params.cfg_scale)),
+            ("SIGMA_MIN", float(params.sigma_min)),
+            ("SIGMA_MAX", float(params.sigma_max)),
+            (
+                "SAMPLER_TYPE",
+                DiffusionModelOpt::Enum(EnumOpt {
+                    value: Some(params.sampler_type),
+                    ..Default::default()
+                }),
+            ),
+            ("DEVICE", device(params.device)),
+            ("SEED", seed(params.seed)),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stable_audio_template() -> DiffusionModelTemplate {
+        serde_json::from_value(serde_json::json!({
+            "options": {
+                "MODEL": { "hidden": true, "kind": "String", "value": "StableAudioOpen" },
+                "POINT": { "hidden": true, "kind": "String", "value": "stabilityai/stable-audio-open-1.0" },
+                "CONDITION_PROMPT": { "kind": "String", "required": true, "value": "drums" },
+                "NEGATIVE_PROMPT": { "kind": "String", "value": "" },
+                "SECONDS_START": { "kind": "Float", "value": 0, "range": { "start": 0, "end": 47 } },
+                "SECONDS_TOTAL": { "kind": "Float", "value": 30, "range": { "start": 0, "end": 47 } },
+                "STEPS": { "kind": "Int", "value": 100, "range": { "start": 1, "end": 200 } },
+                "CFG_SCALE": { "kind": "Float", "value": 3, "range": { "start": 1, "end": 10 } },
+                "SIGMA_MIN": { "kind": "Float", "value": 0.3, "range": { "start": 0, "end": 1000 } },
+                "SIGMA_MAX": { "kind": "Float", "value": 500, "range": { "start": 0, "end": 1000 } },
+                "SAMPLER_TYPE": {
+                    "kind": "Enum",
+                    "value": "dpmpp-3m-sde",
+                    "choices": [
+                        { "value": "dpmpp-3m-sde", "label": "DPM++ 3M SDE" },
+                        { "value": "k-heun", "label": "Heun" }
+                    ]
+                },
+                "DEVICE": { "kind": "String", "value": "auto", "possible_values": ["auto", "cpu"] },
+                "SEED": { "kind": "Seed", "value": null, "random": true }
+            },
+            "rules": [
+                { "rule": "LessThan", "lesser": "SIGMA_MIN", "greater": "SIGMA_MAX" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn stable_audio_params_keep_template_constraints() {
+        let template = stable_audio_template();
+        let mut params = StableAudioParams::try_from(template.clone()).unwrap();
+        params.sampler_type = "k-heun".to_string();
+        params.steps = 50;
+        params.seed = Some(7);
+
+        let applied = params.apply_to(&template).unwrap();
+        assert!(applied.validate().is_empty(), "{:?}", applied.validate());
+        assert_eq!(applied.rules, template.rules);
+        let sampler = applied.options["SAMPLER_TYPE"].as_enum_ref().unwrap();
+        assert_eq!(sampler.choices.len(), 2);
+        assert_eq!(sampler.value.as_deref(), Some("k-heun"));
+        assert_eq!(applied.options["STEPS"].as_int_ref().unwrap().range, Some(1..200));
+        assert!(applied.options["CONDITION_PROMPT"].required());
+    }
+
+    #[test]
+    fn params_out_of_range_are_rejected_by_validation() {
+        let template = stable_audio_template();
+        let mut params = StableAudioParams::try_from(template.clone()).unwrap();
+        params.steps = 1000;
+        let applied = params.apply_to(&template).unwrap();
+        assert_eq!(applied.validate().len(), 1);
+    }
+
+    #[test]
+    fn params_of_another_model_are_rejected() {
+        let template = stable_audio_template();
+        let mut params = StableAudioParams::try_from(template.clone()).unwrap();
+        params.model = "MusicGenSmall".to_string();
+        assert!(matches!(params.apply_to(&template), Err(ParamError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn params_convert_into_an_overrides_template() {
+        let template = stable_audio_template();
+        let params = StableAudioParams::try_from(template.clone()).unwrap();
+        let overrides: DiffusionModelTemplate = params.clone().into();
+        let (merged, report) = template.apply_overrides(&overrides);
+        assert!(report.unknown_keys.is_empty() && report.kind_mismatches.is_empty());
+        assert!(overrides.options["SAMPLER_TYPE"].as_enum_ref().unwrap().choices.is_empty());
+        assert_eq!(StableAudioParams::try_from(merged).unwrap(), params);
+    }
+}
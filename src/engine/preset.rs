@@ -0,0 +1,231 @@
+use crate::prelude::*;
+
+use super::diffusion::DiffusionModelTemplate;
+
+/// Presets bundled with the library, indexed by model name
+const FACTORY_PRESETS: &str = include_str!("./factory_presets.json");
+
+/// Named set of option values applied over the default template of a model
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    pub model: String,
+    /// Only the options overridden by the preset, constraints are taken from the model template
+    pub overrides: DiffusionModelTemplate,
+    /// Factory presets are bundled with the library and are read only
+    #[serde(default)]
+    pub factory: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FactoryPreset {
+    name: String,
+    overrides: DiffusionModelTemplate,
+}
+
+/// User presets stored as `<cache>/presets/<model>/<name>.json`
+#[derive(Debug, Clone)]
+pub struct PresetStore {
+    directory: PathBuf,
+}
+
+impl Preset {
+    pub fn new(name: &str, model: &str, overrides: DiffusionModelTemplate) -> Self {
+        Self {
+            name: name.to_string(),
+            model: model.to_string(),
+            overrides,
+            factory: false,
+        }
+    }
+
+    /// Merge the preset values over `template`, options unknown to the template or
+    /// of a different kind are ignored.
+    pub fn apply(&self, template: DiffusionModelTemplate) -> DiffusionModelTemplate {
+        let (template, report) = template.apply_overrides(&self.overrides);
+        if !report.is_clean() {
+            warn!(preset = self.name, report = ?report, "preset does not match the model template");
+        }
+        template
+    }
+}
+
+impl PresetStore {
+    pub fn new(cache_directory: &Path) -> Self {
+        Self {
+            directory: cache_directory.join("presets"),
+        }
+    }
+
+    pub fn factory_presets(model: &str) -> Vec<Preset> {
+        let mut presets: HashMap<String, Vec<FactoryPreset>> =
+            serde_json::from_str(FACTORY_PRESETS).expect("invalid factory presets");
+        presets
+            .remove(model)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|preset| Preset {
+                name: preset.name,
+                model: model.to_string(),
+                overrides: preset.overrides,
+                factory: true,
+            })
+            .collect()
+    }
+
+    /// Factory presets followed by the user presets of `model`, sorted by name
+    pub async fn list(&self, model: &str) -> CaResult<Vec<Preset>> {
+        let mut presets = Self::factory_presets(model);
+        let mut user_presets = vec![];
+        let mut entries = match tokio::fs::read_dir(self.directory.join(model)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(presets),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|extension| extension == "json") {
+                // a single unreadable preset must not hide the others
+                let preset = match tokio::fs::read(entry.path()).await {
+                    Ok(raw) => serde_json::from_slice::<Preset>(&raw).map_err(CaError::from),
+                    Err(e) => Err(e.into()),
+                };
+                match preset {
+                    Ok(preset) => user_presets.push(preset),
+                    Err(e) => warn!(path = ?entry.path(), "skipped invalid preset: {}", e),
+                }
+            }
+        }
+        user_presets.sort_by(|left, right| left.name.cmp(&right.name));
+        presets.extend(user_presets);
+        Ok(presets)
+    }
+
+    /// Load a user preset, falling back to the factory presets
+    pub async fn load(&self, model: &str, name: &str) -> CaResult<Preset> {
+        match tokio::fs::read(self.path(model, name)?).await {
+            Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::factory_presets(model)
+                .into_iter()
+                .find(|preset| preset.name == name)
+                .ok_or_else(|| CaError::PresetNotFound {
+                    model: model.to_string(),
+                    name: name.to_string(),
+                }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Create or overwrite a user preset
+    pub async fn save(&self, preset: &Preset) -> CaResult<()> {
+        self.ensure_writable(&preset.model, &preset.name)?;
+        let path = self.path(&preset.model, &preset.name)?;
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let preset = Preset {
+            factory: false,
+            ..preset.clone()
+        };
+        tokio::fs::write(path, serde_json::to_vec_pretty(&preset)?).await?;
+        Ok(())
+    }
+
+    /// Renaming to the current name does nothing, renaming over another user preset is an error
+    pub async fn rename(&self, model: &str, name: &str, new_name: &str) -> CaResult<()> {
+        self.ensure_writable(model, name)?;
+        self.ensure_writable(model, new_name)?;
+        if name == new_name {
+            return Ok(());
+        }
+        if tokio::fs::try_exists(self.path(model, new_name)?).await? {
+            return Err(CaError::PresetExists {
+                model: model.to_string(),
+                name: new_name.to_string(),
+            });
+        }
+        let mut preset = self.load(model, name).await?;
+        preset.name = new_name.to_string();
+        self.save(&preset).await?;
+        self.delete(model, name).await
+    }
+
+    pub async fn delete(&self, model: &str, name: &str) -> CaResult<()> {
+        self.ensure_writable(model, name)?;
+        match tokio::fs::remove_file(self.path(model, name)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(CaError::PresetNotFound {
+                model: model.to_string(),
+                name: name.to_string(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn ensure_writable(&self, model: &str, name: &str) -> CaResult<()> {
+        if Self::factory_presets(model).iter().any(|preset| preset.name == name) {
+            return Err(CaError::FactoryPreset(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn path(&self, model: &str, name: &str) -> CaResult<PathBuf> {
+        let invalid = |value: &str| {
+            value.is_empty()
+                || value.starts_with('.')
+                || value.chars().any(|c| r#"<>:"/\|?*"#.contains(c) || c.is_control())
+        };
+        if invalid(model) {
+            return Err(CaError::InvalidPresetName(model.to_string()));
+        }
+        if invalid(name) {
+            return Err(CaError::InvalidPresetName(name.to_string()));
+        }
+        Ok(self.directory.join(model).join(format!("{}.json", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: &str = "MusicGenSmall";
+
+    #[tokio::test]
+    async fn rename_to_same_name_keeps_the_preset() {
+        let directory = TempDir::new("presets").unwrap();
+        let store = PresetStore::new(directory.path());
+        store.save(&Preset::new("dark", MODEL, Default::default())).await.unwrap();
+        store.rename(MODEL, "dark", "dark").await.unwrap();
+        assert_eq!(store.load(MODEL, "dark").await.unwrap().name, "dark");
+    }
+
+    #[tokio::test]
+    async fn rename_does_not_overwrite_an_existing_preset() {
+        let directory = TempDir::new("presets").unwrap();
+        let store = PresetStore::new(directory.path());
+        store.save(&Preset::new("dark", MODEL, Default::default())).await.unwrap();
+        store.save(&Preset::new("bright", MODEL, Default::default())).await.unwrap();
+        assert!(matches!(
+            store.rename(MODEL, "dark", "bright").await,
+            Err(CaError::PresetExists { .. })
+        ));
+        assert!(store.load(MODEL, "dark").await.is_ok());
+        store.rename(MODEL, "dark", "darker").await.unwrap();
+        assert!(store.load(MODEL, "darker").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn list_skips_corrupt_presets() {
+        let directory = TempDir::new("presets").unwrap();
+        let store = PresetStore::new(directory.path());
+        store.save(&Preset::new("dark", MODEL, Default::default())).await.unwrap();
+        std::fs::write(directory.path().join("presets").join(MODEL).join("broken.json"), b"{").unwrap();
+        let names: Vec<String> = store
+            .list(MODEL)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|preset| !preset.factory)
+            .map(|preset| preset.name)
+            .collect();
+        assert_eq!(names, vec!["dark".to_string()]);
+    }
+}
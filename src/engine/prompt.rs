@@ -0,0 +1,160 @@
+use crate::prelude::*;
+
+/// Values supplied by the host (DAW) and available in prompts as `{variable}`.
+///
+/// Built-in variables:
+/// - `bpm`: tempo rounded to the closest integer, `tempo`: tempo with up to two decimals
+/// - `key`: musical key (`C minor`, `F#`, ...)
+/// - `time_signature`: `numerator/denominator`
+/// - `track_name`: name of the host track
+///
+/// Any entry of `variables` is also available, it takes precedence over built-in variables.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct HostContext {
+    pub tempo: Option<f64>,
+    pub key: Option<String>,
+    pub time_signature: Option<(u32, u32)>,
+    pub track_name: Option<String>,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PromptError {
+    #[error("unknown prompt variable {{{name}}} at {position}")]
+    UnknownVariable { name: String, position: usize },
+    #[error("prompt variable {{{name}}} at {position} is not provided by the host")]
+    MissingValue { name: String, position: usize },
+    #[error("invalid prompt variable name {name:?} at {position}")]
+    InvalidName { name: String, position: usize },
+    #[error("unclosed '{{' at {position}, use '{{{{' for a literal brace")]
+    Unclosed { position: usize },
+    #[error("unmatched '}}' at {position}, use '}}}}' for a literal brace")]
+    Unmatched { position: usize },
+}
+
+/// Variables derived from the `HostContext` fields
+pub const BUILTIN_VARIABLES: &[&str] = &["bpm", "tempo", "key", "time_signature", "track_name"];
+
+impl HostContext {
+    /// Value of a variable, `Ok(None)` for a built-in variable the host did not provide
+    fn variable(&self, name: &str) -> Result<Option<String>, ()> {
+        if let Some(value) = self.variables.get(name) {
+            return Ok(Some(value.clone()));
+        }
+        match name {
+            "bpm" => Ok(self.tempo.map(|tempo| format!("{}", tempo.round()))),
+            "tempo" => Ok(self.tempo.map(|tempo| format!("{}", (tempo * 100.0).round() / 100.0))),
+            "key" => Ok(self.key.clone()),
+            "time_signature" => Ok(self
+                .time_signature
+                .map(|(numerator, denominator)| format!("{}/{}", numerator, denominator))),
+            "track_name" => Ok(self.track_name.clone()),
+            _ => Err(()),
+        }
+    }
+
+    /// Replace every `{variable}` of `prompt`, variable names are made of ASCII letters, digits and `_`.
+    /// `{{` and `}}` are literal braces. Positions reported by errors are byte offsets in `prompt`.
+    pub fn expand(&self, prompt: &str) -> Result<String, PromptError> {
+        let mut output = String::with_capacity(prompt.len());
+        let mut chars = prompt.char_indices().peekable();
+        while let Some((position, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().is_some_and(|(_, next)| *next == '{') => {
+                    chars.next();
+                    output.push('{');
+                }
+                '}' if chars.peek().is_some_and(|(_, next)| *next == '}') => {
+                    chars.next();
+                    output.push('}');
+                }
+                '}' => return Err(PromptError::Unmatched { position }),
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => name.push(c),
+                            None => return Err(PromptError::Unclosed { position }),
+                        }
+                    }
+                    let name = name.trim().to_string();
+                    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                        return Err(PromptError::InvalidName { name, position });
+                    }
+                    match self.variable(&name) {
+                        Ok(Some(value)) => output.push_str(&value),
+                        Ok(None) => return Err(PromptError::MissingValue { name, position }),
+                        Err(()) => return Err(PromptError::UnknownVariable { name, position }),
+                    }
+                }
+                c => output.push(c),
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> HostContext {
+        HostContext {
+            tempo: Some(127.456),
+            key: Some("C minor".to_string()),
+            time_signature: Some((7, 8)),
+            track_name: None,
+            variables: HashMap::from([("genre".to_string(), "techno".to_string())]),
+        }
+    }
+
+    #[test]
+    fn builtin_and_custom_variables_are_expanded() {
+        let expanded = context()
+            .expand("{genre} in {key} at {bpm} bpm ({tempo}), { time_signature }")
+            .unwrap();
+        assert_eq!(expanded, "techno in C minor at 127 bpm (127.46), 7/8");
+    }
+
+    #[test]
+    fn custom_variables_take_precedence() {
+        let mut context = context();
+        context.variables.insert("key".to_string(), "A major".to_string());
+        assert_eq!(context.expand("{key}").unwrap(), "A major");
+    }
+
+    #[test]
+    fn double_braces_are_literal() {
+        assert_eq!(context().expand("{{key}} }}{{").unwrap(), "{key} }{");
+    }
+
+    #[test]
+    fn invalid_prompts_report_their_position() {
+        let context = context();
+        assert_eq!(
+            context.expand("a {mood}"),
+            Err(PromptError::UnknownVariable {
+                name: "mood".to_string(),
+                position: 2
+            })
+        );
+        assert_eq!(
+            context.expand("{track_name}"),
+            Err(PromptError::MissingValue {
+                name: "track_name".to_string(),
+                position: 0
+            })
+        );
+        assert_eq!(
+            context.expand("{bad name}"),
+            Err(PromptError::InvalidName {
+                name: "bad name".to_string(),
+                position: 0
+            })
+        );
+        assert_eq!(context.expand("ab {key"), Err(PromptError::Unclosed { position: 3 }));
+        assert_eq!(context.expand("a}b"), Err(PromptError::Unmatched { position: 1 }));
+    }
+}
@@ -0,0 +1,256 @@
+use sha2::{Digest, Sha256};
+
+use crate::prelude::*;
+
+use super::asset::Asset;
+
+/// Content addressed asset store: files are named by the SHA-256 of their content under
+/// `<root>/objects/<first two hex digits>/<hash>.<extension>`, identical audio is stored once
+/// and human friendly names are mapped onto hashes in `<root>/names.json`.
+#[derive(Debug, Clone)]
+pub struct AssetStore {
+    root: PathBuf,
+    names: Arc<RwLock<BTreeMap<String, ContentHash>>>,
+}
+
+/// Lowercase hex SHA-256 of a file content
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ContentHash(String);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredObject {
+    pub hash: ContentHash,
+    pub asset: Asset,
+    /// The content was already in the store
+    pub deduplicated: bool,
+}
+
+impl ContentHash {
+    pub fn of_bytes(content: &[u8]) -> Self {
+        let digest = Sha256::digest(content);
+        Self(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    pub async fn of(path: &Path) -> CaResult<Self> {
+        let content = tokio::fs::read(path).await?;
+        tokio::task::spawn_blocking(move || Self::of_bytes(&content))
+            .await
+            .map_err(CaError::from)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::str::FromStr for ContentHash {
+    type Err = CaError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.len() != 64 || !value.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+            return Err(CaError::InvalidContentHash(value.to_string()));
+        }
+        Ok(Self(value.to_string()))
+    }
+}
+
+impl TryFrom<String> for ContentHash {
+    type Error = CaError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ContentHash> for String {
+    fn from(hash: ContentHash) -> Self {
+        hash.0
+    }
+}
+
+impl AssetStore {
+    pub async fn open(root: &Path) -> CaResult<Self> {
+        let names = match tokio::fs::read(root.join("names.json")).await {
+            Ok(raw) => serde_json::from_slice(&raw)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            root: root.to_owned(),
+            names: Arc::new(RwLock::new(names)),
+        })
+    }
+
+    /// Copy an asset and its metadata sidecar into the store, the source is left untouched
+    pub async fn put(&self, asset: &Asset) -> CaResult<StoredObject> {
+        let content = tokio::fs::read(asset.path()).await?;
+        let extension = asset
+            .path()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_string();
+        let stored = self.put_bytes(content, &extension).await?;
+        let sidecar = stored.asset.sidecar_path();
+        if !tokio::fs::try_exists(&sidecar).await? && tokio::fs::try_exists(asset.sidecar_path()).await? {
+            tokio::fs::copy(asset.sidecar_path(), sidecar).await?;
+        }
+        Ok(stored)
+    }
+
+    pub async fn put_bytes(&self, content: Vec<u8>, extension: &str) -> CaResult<StoredObject> {
+        let (hash, content) = tokio::task::spawn_blocking(move || (ContentHash::of_bytes(&content), content))
+            .await?;
+        // the same content may already be stored under another extension
+        if let Some(path) = self.find(&hash).await? {
+            return Ok(StoredObject {
+                hash,
+                asset: Asset::Stored { path },
+                deduplicated: true,
+            });
+        }
+        let path = self.object_path(&hash, extension);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // write then rename so an interrupted write never leaves a truncated object under its hash
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(StoredObject {
+            hash,
+            asset: Asset::Stored { path },
+            deduplicated: false,
+        })
+    }
+
+    /// Object of `hash`, its content is hashed again and must match
+    pub async fn load(&self, hash: &ContentHash) -> CaResult<Asset> {
+        let path = self.find(hash).await?.ok_or_else(|| CaError::ObjectNotFound(hash.to_string()))?;
+        let found = ContentHash::of(&path).await?;
+        if &found != hash {
+            return Err(CaError::IntegrityMismatch {
+                expected: hash.to_string(),
+                found: found.to_string(),
+            });
+        }
+        Ok(Asset::Stored { path })
+    }
+
+    pub async fn contains(&self, hash: &ContentHash) -> CaResult<bool> {
+        Ok(self.find(hash).await?.is_some())
+    }
+
+    /// Point `name` to `hash`, replacing the previous target of the name
+    pub async fn set_name(&self, name: &str, hash: &ContentHash) -> CaResult<()> {
+        if name.trim().is_empty() {
+            return Err(CaError::Empty);
+        }
+        if !self.contains(hash).await? {
+            return Err(CaError::ObjectNotFound(hash.to_string()));
+        }
+        let mut names = self.names.write().await;
+        names.insert(name.to_string(), hash.clone());
+        self.persist(&names).await
+    }
+
+    pub async fn remove_name(&self, name: &str) -> CaResult<Option<ContentHash>> {
+        let mut names = self.names.write().await;
+        let hash = names.remove(name);
+        self.persist(&names).await?;
+        Ok(hash)
+    }
+
+    pub async fn resolve(&self, name: &str) -> Option<ContentHash> {
+        self.names.read().await.get(name).cloned()
+    }
+
+    pub async fn names(&self) -> BTreeMap<String, ContentHash> {
+        self.names.read().await.clone()
+    }
+
+    pub async fn load_named(&self, name: &str) -> CaResult<Asset> {
+        let hash = self
+            .resolve(name)
+            .await
+            .ok_or_else(|| CaError::ObjectNotFound(name.to_string()))?;
+        self.load(&hash).await
+    }
+
+    fn shard(&self, hash: &ContentHash) -> PathBuf {
+        self.root.join("objects").join(&hash.as_str()[..2])
+    }
+
+    fn object_path(&self, hash: &ContentHash, extension: &str) -> PathBuf {
+        let shard = self.shard(hash);
+        match extension {
+            "" => shard.join(hash.as_str()),
+            extension => shard.join(format!("{}.{}", hash, extension)),
+        }
+    }
+
+    /// Objects are looked up by file stem since the extension is not part of the address
+    async fn find(&self, hash: &ContentHash) -> CaResult<Option<PathBuf>> {
+        let mut entries = match tokio::fs::read_dir(self.shard(hash)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(".json") || name.ends_with(".tmp") {
+                continue;
+            }
+            if path.file_stem().is_some_and(|stem| stem == hash.as_str()) {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn persist(&self, names: &BTreeMap<String, ContentHash>) -> CaResult<()> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        let path = self.root.join("names.json");
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(names)?).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn same_content_is_stored_once_whatever_the_extension() {
+        let root = TempDir::new("crovai_store").unwrap();
+        let store = AssetStore::open(root.path()).await.unwrap();
+        let first = store.put_bytes(b"RIFF....".to_vec(), "wav").await.unwrap();
+        let second = store.put_bytes(b"RIFF....".to_vec(), "wave").await.unwrap();
+        assert!(!first.deduplicated);
+        assert!(second.deduplicated);
+        assert_eq!(first.asset, second.asset);
+        assert_eq!(store.load(&first.hash).await.unwrap(), first.asset);
+    }
+
+    #[test]
+    fn content_hash_is_validated_when_deserialized() {
+        let hash = ContentHash::of_bytes(b"audio");
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{}\"", hash));
+        assert_eq!(serde_json::from_str::<ContentHash>(&json).unwrap(), hash);
+        assert!(serde_json::from_str::<ContentHash>("\"a\"").is_err());
+        assert!(serde_json::from_str::<ContentHash>(&json.to_uppercase()).is_err());
+    }
+}
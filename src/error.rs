@@ -1,6 +1,9 @@
 use std::process::ExitStatus;
 
 use crate::prelude::*;
+use crate::engine::diffusion::OptionViolation;
+use crate::engine::params::ParamError;
+use crate::engine::prompt::PromptError;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -17,12 +20,52 @@ pub enum CaError {
     Poisoned,
     #[error("asset not found: {0}")]
     AssetNotFound(usize),
+    #[error("asset file not found: {0:?}")]
+    MissingAsset(PathBuf),
     #[error("hound: {0}")]
     HoundError(#[from] hound::Error),
+    #[error("unsupported audio format: {0}")]
+    UnsupportedFormat(String),
+    #[error("no object or name {0} in the asset store")]
+    ObjectNotFound(String),
+    #[error("invalid content hash: {0:?}")]
+    InvalidContentHash(String),
+    #[error("corrupted object: expected hash {expected}, found {found}")]
+    IntegrityMismatch { expected: String, found: String },
+    #[error("invalid edit: {0}")]
+    InvalidEdit(String),
+    #[error("operation canceled")]
+    Canceled,
+    #[error("background task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
     #[error("expected non empty value")]
     Empty,
     #[error("incorect buffer configuration")]
     CoruptedBuffer,
+    #[error("invalid template: {}", .0.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", "))]
+    InvalidTemplate(Vec<OptionViolation>),
+    #[error(transparent)]
+    Param(#[from] ParamError),
+    #[error(transparent)]
+    Prompt(#[from] PromptError),
+    #[error("unknown host parameter: {0}")]
+    UnknownParameter(u32),
+    #[error("preset not found: {model}/{name}")]
+    PresetNotFound {
+        model: String,
+        name: String,
+    },
+    #[error("preset already exists: {model}/{name}")]
+    PresetExists {
+        model: String,
+        name: String,
+    },
+    #[error("invalid preset name: {0:?}")]
+    InvalidPresetName(String),
+    #[error("factory preset is read only: {0}")]
+    FactoryPreset(String),
+    #[error("invalid long form configuration: {0}")]
+    InvalidLongFormOpts(String),
     #[error("externaly occured error: {message:?}")]
     ExternalError {
         message: Option<String>,
@@ -5,7 +5,7 @@ pub (crate)use std::collections::{HashMap, HashSet, VecDeque, BTreeMap, BTreeSet
 pub (crate)use std::ops::Range;
 pub (crate)use serde::{Deserialize, Serialize};
 
-pub (crate)use chrono::Local;
+pub (crate)use chrono::{DateTime, Local};
 pub (crate)use tempdir::TempDir;
 pub (crate) use fundsp::prelude::*;
 